@@ -0,0 +1,410 @@
+use anyhow::{Context, bail};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command as Process, ExitCode},
+    time::Duration,
+};
+
+use crate::config::Config;
+use crate::entry::Document;
+use crate::kdf::{self, KdfPolicy};
+use crate::lock::{VaultLock, lock_path, lock_vault};
+use crate::recipients::{
+    Recipient, load_recipients, recipient_changes, recipients_path, remember_recipients,
+    store_recipients,
+};
+use crate::store::{Keys, generate_keyfile, load_pdpw_file, store_pdpw_file};
+use crate::totp::Totp;
+
+const DEFAULT_BENCH_TARGET: Duration = Duration::from_secs(1);
+
+/// Headless operations that work on a vault without starting the GUI.
+#[derive(Debug, Clone)]
+pub(crate) enum Command {
+    /// Decrypt the vault to stdout
+    Cat,
+    /// Edit the decrypted vault with `$VISUAL` / `$EDITOR`
+    Edit,
+    /// Print all lines of the vault containing the pattern
+    Grep(String),
+    /// Print the current TOTP code of the entry best matching the name
+    Totp(String),
+    /// Change the master password (and keyfile) of the vault
+    Passwd(KeyfileChange),
+    /// Measure the scrypt work factors this machine can afford
+    BenchKdf,
+    /// Write a new random keyfile to the given path
+    GenKeyfile(PathBuf),
+}
+
+/// What `passwd` does with the keyfile of the vault.
+#[derive(Debug, Clone, Default)]
+pub(crate) enum KeyfileChange {
+    #[default]
+    Keep,
+    Set(PathBuf),
+    Remove,
+}
+
+/// Runs `command`, returning the exit status for `main` so that everything
+/// holding decrypted data is dropped, and zeroized, before the process ends.
+pub(crate) fn run(
+    command: &Command,
+    pdpw_file: &Path,
+    config: &Config,
+) -> anyhow::Result<ExitCode> {
+    if let Command::BenchKdf = command {
+        let target = match config.kdf {
+            KdfPolicy::Target(target) => target,
+            _ => DEFAULT_BENCH_TARGET,
+        };
+        kdf::bench(target);
+        return Ok(ExitCode::SUCCESS);
+    }
+    if let Command::GenKeyfile(path) = command {
+        generate_keyfile(path)?;
+        eprintln!(
+            "Wrote {}, keep a copy in a safe place: without it the vault cannot be opened",
+            path.display()
+        );
+        return Ok(ExitCode::SUCCESS);
+    }
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        match command {
+            Command::Cat => cat(pdpw_file, config, &mut std::io::stdout().lock()).await?,
+            Command::Edit => {
+                let (config, recipients) = save_recipients(pdpw_file, config).await?;
+                edit(pdpw_file, &config, &recipients).await?;
+            }
+            Command::Grep(pattern) => {
+                if !grep(pattern, pdpw_file, config, &mut std::io::stdout().lock()).await? {
+                    // behave like grep(1)
+                    return Ok(ExitCode::FAILURE);
+                }
+            }
+            Command::Totp(name) => totp(name, pdpw_file, config).await?,
+            Command::Passwd(keyfile) => {
+                let (config, recipients) = save_recipients(pdpw_file, config).await?;
+                passwd(pdpw_file, &config, keyfile, &recipients).await?;
+            }
+            Command::BenchKdf | Command::GenKeyfile(_) => {}
+        }
+        Ok(ExitCode::SUCCESS)
+    })
+}
+
+/// The recipients to save the vault to, agreeing with the GUI: those of the
+/// recipients file plus the ones given on the command line. Only commands
+/// that save need them, reading works without a valid recipients file.
+async fn save_recipients(
+    pdpw_file: &Path,
+    config: &Config,
+) -> anyhow::Result<(Config, Vec<Recipient>)> {
+    let mut recipients = load_recipients(pdpw_file).await?;
+    confirm_recipients(pdpw_file, &recipients).await?;
+    for key in &config.recipients {
+        if !recipients.iter().any(|r| r.matches(key)) {
+            recipients.push(Recipient {
+                key: key.clone(),
+                label: "command line".into(),
+            });
+        }
+    }
+    let config = Config {
+        recipients: recipients.iter().map(|r| r.key.clone()).collect(),
+        ..config.clone()
+    };
+    Ok((config, recipients))
+}
+
+/// Asks before saving the vault to a recipients file that changed since the
+/// current user last saved it.
+async fn confirm_recipients(pdpw_file: &Path, recipients: &[Recipient]) -> anyhow::Result<()> {
+    let changes = recipient_changes(pdpw_file, recipients).await?;
+    if changes.is_empty() {
+        return Ok(());
+    }
+    eprint!(
+        "The recipients file changed since your last save:\n{changes}\
+         Encrypt the vault to these recipients? [y/N] "
+    );
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    if !answer.trim().eq_ignore_ascii_case("y") {
+        bail!("Check {} first", recipients_path(pdpw_file).display());
+    }
+    Ok(())
+}
+
+fn ensure_exists(pdpw_file: &Path) -> anyhow::Result<()> {
+    if !pdpw_file.exists() {
+        bail!("{} does not exist", pdpw_file.display());
+    }
+    Ok(())
+}
+
+async fn lock(pdpw_file: &Path, config: &Config) -> anyhow::Result<VaultLock> {
+    match lock_vault(pdpw_file, config.break_lock).await? {
+        Ok(lock) => Ok(lock),
+        Err(owner) => bail!(
+            "{} is locked by {owner}{}, use --break-lock if it is stale or remove {}",
+            pdpw_file.display(),
+            if owner.is_stale() {
+                " which is gone"
+            } else {
+                ""
+            },
+            lock_path(pdpw_file).display()
+        ),
+    }
+}
+
+async fn totp(name: &str, pdpw_file: &Path, config: &Config) -> anyhow::Result<()> {
+    ensure_exists(pdpw_file)?;
+    let keys = unlock_keys(config, "Password: ")?;
+    let passwords = load_pdpw_file(pdpw_file, &keys).await?;
+    let document = Document::parse(&passwords);
+    let candidates = || document.entries().filter(|entry| entry.totp().is_some());
+    // an exact title wins over the best fuzzy match
+    let entry = candidates()
+        .find(|entry| entry.title().eq_ignore_ascii_case(name))
+        .or_else(|| candidates().max_by_key(|entry| entry.score(name)))
+        .filter(|entry| entry.score(name).is_some())
+        .with_context(|| format!("No entry matching {name} has a TOTP secret"))?;
+    let totp = Totp::parse(entry.totp().unwrap_or_default())
+        .with_context(|| format!("Invalid TOTP secret in {}", entry.title()))?;
+    let (code, left) = totp.now();
+    println!("{code}");
+    eprintln!("{}: valid for {}s", entry.title(), left.as_secs());
+    Ok(())
+}
+
+fn prompt_pin(prompt: &str) -> anyhow::Result<String> {
+    rpassword::prompt_password(prompt).context("Couldn't read the password")
+}
+
+fn unlock_keys(config: &Config, prompt: &str) -> anyhow::Result<Keys> {
+    let pin = match config.passphrase.read()? {
+        Some(pin) => pin,
+        // identity files are enough to open a vault encrypted to recipients
+        None if !config.identities.is_empty() => String::new(),
+        None => prompt_pin(prompt)?,
+    };
+    Ok(config.keys(&pin))
+}
+
+async fn cat(pdpw_file: &Path, config: &Config, out: &mut impl Write) -> anyhow::Result<()> {
+    ensure_exists(pdpw_file)?;
+    let keys = unlock_keys(config, "Password: ")?;
+    let passwords = load_pdpw_file(pdpw_file, &keys).await?;
+    out.write_all(passwords.as_bytes())?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Prints the matching lines, returns whether there were any.
+async fn grep(
+    pattern: &str,
+    pdpw_file: &Path,
+    config: &Config,
+    out: &mut impl Write,
+) -> anyhow::Result<bool> {
+    ensure_exists(pdpw_file)?;
+    let keys = unlock_keys(config, "Password: ")?;
+    let passwords = load_pdpw_file(pdpw_file, &keys).await?;
+    let mut found = false;
+    for line in passwords.lines().filter(|line| line.contains(pattern)) {
+        writeln!(out, "{line}")?;
+        found = true;
+    }
+    Ok(found)
+}
+
+async fn passwd(
+    pdpw_file: &Path,
+    config: &Config,
+    keyfile: &KeyfileChange,
+    recipients: &[Recipient],
+) -> anyhow::Result<()> {
+    ensure_exists(pdpw_file)?;
+    let _lock = lock(pdpw_file, config).await?;
+    let keys = unlock_keys(config, "Old password: ")?;
+    let passwords = load_pdpw_file(pdpw_file, &keys).await?;
+    let new_pin = prompt_pin("New password: ")?;
+    if new_pin.is_empty() {
+        bail!("The new password must not be empty");
+    }
+    if new_pin != prompt_pin("Repeat new password: ")? {
+        bail!("The new passwords do not match");
+    }
+    let previous = keys;
+    let mut keys = config.keys(&new_pin);
+    keys.previous = Some(Box::new(previous));
+    match keyfile {
+        KeyfileChange::Keep => {}
+        KeyfileChange::Set(path) => keys.keyfile = Some(path.clone()),
+        KeyfileChange::Remove => keys.keyfile = None,
+    }
+    store_pdpw_file(
+        pdpw_file,
+        &keys,
+        &passwords,
+        config.backup_count,
+        config.armor,
+    )
+    .await?;
+    store_recipients(pdpw_file, recipients).await?;
+    remember_recipients(pdpw_file, recipients).await
+}
+
+async fn edit(pdpw_file: &Path, config: &Config, recipients: &[Recipient]) -> anyhow::Result<()> {
+    ensure_exists(pdpw_file)?;
+    let _lock = lock(pdpw_file, config).await?;
+    let keys = unlock_keys(config, "Password: ")?;
+    let passwords = load_pdpw_file(pdpw_file, &keys).await?;
+
+    // tempfile creates the file readable for the current user only
+    let mut plain = tempfile::Builder::new()
+        .prefix(".pdpw-")
+        .tempfile_in(plain_text_dir())
+        .context("Couldn't create a temporary file")?;
+    plain.write_all(passwords.as_bytes())?;
+    plain.as_file().sync_all()?;
+
+    let result = run_editor(plain.path());
+    let edited = result.and_then(|()| Ok(std::fs::read_to_string(plain.path())?));
+    // editors may replace the file instead of writing into it, so go by path
+    shred(plain.path())?;
+    let edited = edited?;
+
+    if edited == passwords {
+        eprintln!("No changes");
+        return Ok(());
+    }
+    store_pdpw_file(pdpw_file, &keys, &edited, config.backup_count, config.armor).await?;
+    store_recipients(pdpw_file, recipients).await?;
+    remember_recipients(pdpw_file, recipients).await
+}
+
+/// Prefer a RAM backed location for the decrypted vault where available.
+fn plain_text_dir() -> PathBuf {
+    let shm = Path::new("/dev/shm");
+    if shm.is_dir() {
+        shm.to_path_buf()
+    } else {
+        std::env::temp_dir()
+    }
+}
+
+fn run_editor(path: &Path) -> anyhow::Result<()> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().context("$EDITOR is empty")?;
+    let status = Process::new(program)
+        .args(parts)
+        .arg(path)
+        .status()
+        .with_context(|| format!("Couldn't start editor {program}"))?;
+    if !status.success() {
+        bail!("Editor {program} exited with {status}");
+    }
+    Ok(())
+}
+
+/// Overwrites the file contents with zeros before it gets deleted.
+fn shred(path: &Path) -> anyhow::Result<()> {
+    let mut file = std::fs::OpenOptions::new().write(true).open(path)?;
+    let len = file.metadata()?.len();
+    let zeros = vec![0u8; 4096];
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = usize::try_from(remaining.min(zeros.len() as u64))?;
+        file.write_all(&zeros[..chunk])?;
+        remaining -= chunk as u64;
+    }
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::passphrase::PassphraseSource;
+
+    const VAULT: &str = "# Mail\nuser: me\npassword: hunter2\n";
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    /// A vault protected by a pin only, which `config` reads from a file,
+    /// next to a recipients file that doesn't parse.
+    fn pin_vault(dir: &Path) -> (PathBuf, Config) {
+        let pin_file = dir.join("pin");
+        std::fs::write(&pin_file, "secret\n").unwrap();
+        let config = Config {
+            passphrase: PassphraseSource::File(pin_file),
+            kdf: KdfPolicy::LogN(10),
+            ..Config::default()
+        };
+        let vault = dir.join("vault.pdpw");
+        block_on(store_pdpw_file(
+            &vault,
+            &config.keys("secret"),
+            VAULT,
+            0,
+            None,
+        ))
+        .unwrap();
+        std::fs::write(recipients_path(&vault), "not a key\n").unwrap();
+        (vault, config)
+    }
+
+    #[test]
+    fn cat_decrypts_pin_vaults() {
+        let dir = tempfile::tempdir().unwrap();
+        let (vault, config) = pin_vault(dir.path());
+        let mut out = vec![];
+        block_on(cat(&vault, &config, &mut out)).unwrap();
+        assert_eq!(out, VAULT.as_bytes());
+        assert_eq!(
+            run(&Command::Cat, &vault, &config).unwrap(),
+            ExitCode::SUCCESS
+        );
+    }
+
+    #[test]
+    fn grep_fails_without_matches() {
+        let dir = tempfile::tempdir().unwrap();
+        let (vault, config) = pin_vault(dir.path());
+        let mut out = vec![];
+        assert!(block_on(grep("user", &vault, &config, &mut out)).unwrap());
+        assert_eq!(out, b"user: me\n");
+        let grep = |pattern: &str| run(&Command::Grep(pattern.into()), &vault, &config).unwrap();
+        assert_eq!(grep("hunter2"), ExitCode::SUCCESS);
+        assert_eq!(grep("nothing"), ExitCode::FAILURE);
+    }
+
+    #[test]
+    fn only_saving_needs_valid_recipients() {
+        let dir = tempfile::tempdir().unwrap();
+        let (vault, config) = pin_vault(dir.path());
+        let totp = Command::Totp("Mail".into());
+        // reading fails on the vault, not on the recipients file
+        let error = run(&totp, &vault, &config).unwrap_err();
+        assert!(error.to_string().contains("TOTP"), "{error}");
+        let passwd = Command::Passwd(KeyfileChange::Keep);
+        assert!(run(&passwd, &vault, &config).is_err());
+        assert!(run(&Command::Edit, &vault, &config).is_err());
+    }
+}
@@ -0,0 +1,76 @@
+use anyhow::Context;
+use std::sync::{Mutex, PoisonError};
+
+/// Kept for the whole session: on X11 and Wayland the clipboard contents are
+/// served by this instance and vanish once it is dropped.
+static CLIPBOARD: Mutex<Option<arboard::Clipboard>> = Mutex::new(None);
+
+fn with_clipboard<T>(
+    f: impl FnOnce(&mut arboard::Clipboard) -> Result<T, arboard::Error>,
+) -> anyhow::Result<T> {
+    let mut clipboard = CLIPBOARD.lock().unwrap_or_else(PoisonError::into_inner);
+    let clipboard = match &mut *clipboard {
+        Some(clipboard) => clipboard,
+        empty @ None => {
+            empty.insert(arboard::Clipboard::new().context("Couldn't access the clipboard!")?)
+        }
+    };
+    Ok(f(clipboard)?)
+}
+
+/// Copies a secret, asking clipboard managers not to keep it.
+pub(crate) fn copy(text: &str) -> anyhow::Result<()> {
+    with_clipboard(|clipboard| sensitive(clipboard.set()).text(text))
+}
+
+/// Sets `x-kde-passwordManagerHint: secret`, which Klipper and most history
+/// tools on X11 and Wayland honor. Only the regular clipboard is written,
+/// never the PRIMARY selection.
+#[cfg(all(
+    unix,
+    not(any(target_os = "macos", target_os = "android", target_os = "emscripten"))
+))]
+fn sensitive(set: arboard::Set<'_>) -> arboard::Set<'_> {
+    use arboard::{LinuxClipboardKind, SetExtLinux};
+    set.clipboard(LinuxClipboardKind::Clipboard)
+        .exclude_from_history()
+}
+
+/// Sets `org.nspasteboard.ConcealedType`.
+#[cfg(target_os = "macos")]
+fn sensitive(set: arboard::Set<'_>) -> arboard::Set<'_> {
+    use arboard::SetExtApple;
+    set.exclude_from_history()
+}
+
+/// Keeps the text out of the clipboard history, cloud clipboard and
+/// clipboard monitors.
+#[cfg(windows)]
+fn sensitive(set: arboard::Set<'_>) -> arboard::Set<'_> {
+    use arboard::SetExtWindows;
+    set.exclude_from_history()
+        .exclude_from_cloud()
+        .exclude_from_monitoring()
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "emscripten",
+    not(any(unix, windows))
+))]
+fn sensitive(set: arboard::Set<'_>) -> arboard::Set<'_> {
+    set
+}
+
+/// Clears the clipboard unless something else was copied since `copied`.
+pub(crate) fn clear_if_unchanged(copied: &str) -> anyhow::Result<()> {
+    with_clipboard(|clipboard| match clipboard.get_text() {
+        Ok(current) if current == copied => clipboard.clear(),
+        _ => Ok(()),
+    })
+}
+
+pub(crate) fn clear() -> anyhow::Result<()> {
+    with_clipboard(arboard::Clipboard::clear)
+        .context("Something went wrong while trying to clean the clipboard")
+}
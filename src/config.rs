@@ -0,0 +1,196 @@
+use anyhow::{Context, bail};
+use std::{path::PathBuf, time::Duration};
+
+use crate::kdf::{KdfPolicy, parse_work_factor};
+use crate::passphrase::PassphraseSource;
+use crate::store::Keys;
+
+const DEFAULT_BACKUP_COUNT: usize = 5;
+const DEFAULT_LOCK_AFTER: Duration = Duration::from_mins(5);
+const DEFAULT_CLEAR_CLIPBOARD_AFTER: Duration = Duration::from_secs(30);
+const CONFIG_FILE: &str = "pdpw/config";
+
+/// Settings that control how a vault is handled once it is opened.
+#[derive(Debug, Clone)]
+pub(crate) struct Config {
+    /// Number of previous vault versions kept as `<vault>.1 .. <vault>.N`.
+    pub(crate) backup_count: usize,
+    /// Where the master password to unlock the vault comes from.
+    pub(crate) passphrase: PassphraseSource,
+    /// age or SSH public keys the vault gets encrypted to.
+    pub(crate) recipients: Vec<String>,
+    /// age or SSH private key files used to decrypt the vault.
+    pub(crate) identities: Vec<PathBuf>,
+    /// File that has to be given together with the master password.
+    pub(crate) keyfile: Option<PathBuf>,
+    /// How expensive unlocking the vault with the master password is.
+    pub(crate) kdf: KdfPolicy,
+    /// Write ASCII armored (`Some(true)`) or binary vaults, `None` keeps the
+    /// format of the existing file.
+    pub(crate) armor: Option<bool>,
+    /// Lock the editor after this long without keyboard or mouse input,
+    /// `None` never locks.
+    pub(crate) lock_after: Option<Duration>,
+    /// Clear text copied from the vault off the clipboard after this long,
+    /// `None` leaves it there until pdpw exits.
+    pub(crate) clear_clipboard_after: Option<Duration>,
+    /// Wordlist for generated passphrases instead of the built-in one.
+    pub(crate) wordlist: Option<PathBuf>,
+    /// Take over the lock of the vault even if another instance holds it.
+    pub(crate) break_lock: bool,
+}
+
+impl Config {
+    pub(crate) fn keys(&self, pin: &str) -> Keys {
+        Keys {
+            pin: pin.to_string(),
+            keyfile: self.keyfile.clone(),
+            recipients: self.recipients.clone(),
+            identity_files: self.identities.clone(),
+            kdf: self.kdf,
+            previous: None,
+        }
+    }
+
+    /// `$XDG_CONFIG_HOME/pdpw/config` or the platform equivalent.
+    pub(crate) fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join(CONFIG_FILE))
+    }
+
+    /// Loads the defaults overridden by the config file, if there is one.
+    ///
+    /// The file holds `key = value` lines, `#` starts a comment:
+    ///
+    /// ```text
+    /// backups = 5
+    /// kdf_log_n = 20        # or
+    /// kdf_target_ms = 1000
+    /// armor = true
+    /// keyfile = /media/usb/vault.key
+    /// lock_after_secs = 300  # 0 never locks
+    /// clear_clipboard_secs = 30
+    /// wordlist = ~/eff_large_wordlist.txt
+    /// ```
+    pub(crate) fn load() -> anyhow::Result<Self> {
+        let mut config = Self::default();
+        let Some(path) = Self::path().filter(|p| p.exists()) else {
+            return Ok(config);
+        };
+        let data = std::fs::read_to_string(&path)
+            .with_context(|| format!("Couldn't read {}", path.display()))?;
+        for (number, line) in data.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                bail!("{}:{}: expected `key = value`", path.display(), number + 1);
+            };
+            let value = value.trim().trim_matches('"');
+            if let Err(e) = config.set(key.trim(), value) {
+                bail!("{}:{}: {e}", path.display(), number + 1);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Applies a single setting, shared by the config file and the CLI.
+    pub(crate) fn set(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        match key {
+            "backups" => {
+                self.backup_count = value.parse().context("backups expects a number")?;
+            }
+            "kdf_log_n" => {
+                self.kdf = KdfPolicy::LogN(
+                    parse_work_factor(value).context("kdf_log_n expects a number from 10 to 30")?,
+                );
+            }
+            "kdf_target_ms" => {
+                let millis = value
+                    .parse()
+                    .context("kdf_target_ms expects a number of milliseconds")?;
+                self.kdf = KdfPolicy::Target(Duration::from_millis(millis));
+            }
+            "armor" => {
+                self.armor = Some(value.parse().context("armor expects true or false")?);
+            }
+            "lock_after_secs" => {
+                let secs = value
+                    .parse()
+                    .context("lock_after_secs expects a number of seconds")?;
+                self.lock_after = (secs > 0).then(|| Duration::from_secs(secs));
+            }
+            "clear_clipboard_secs" => {
+                let secs = value
+                    .parse()
+                    .context("clear_clipboard_secs expects a number of seconds")?;
+                self.clear_clipboard_after = (secs > 0).then(|| Duration::from_secs(secs));
+            }
+            "keyfile" => {
+                self.keyfile = (!value.is_empty()).then(|| expand_home(value));
+            }
+            "wordlist" => {
+                self.wordlist = (!value.is_empty()).then(|| expand_home(value));
+            }
+            _ => bail!("unknown setting {key}"),
+        }
+        Ok(())
+    }
+}
+
+/// Expands a leading `~` to the home directory, the way a shell would.
+fn expand_home(value: &str) -> PathBuf {
+    let rest = match value.strip_prefix('~') {
+        Some("") => "",
+        Some(rest) if rest.starts_with(['/', std::path::MAIN_SEPARATOR]) => &rest[1..],
+        _ => return value.into(),
+    };
+    match dirs::home_dir() {
+        Some(home) => home.join(rest),
+        None => value.into(),
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            backup_count: DEFAULT_BACKUP_COUNT,
+            passphrase: PassphraseSource::default(),
+            recipients: Vec::new(),
+            identities: Vec::new(),
+            keyfile: None,
+            kdf: KdfPolicy::default(),
+            armor: None,
+            lock_after: Some(DEFAULT_LOCK_AFTER),
+            clear_clipboard_after: Some(DEFAULT_CLEAR_CLIPBOARD_AFTER),
+            wordlist: None,
+            break_lock: false,
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expands_home() {
+        let home = dirs::home_dir().unwrap();
+        assert_eq!(expand_home("~"), home);
+        assert_eq!(expand_home("~/words.txt"), home.join("words.txt"));
+        assert_eq!(
+            expand_home("~other/words.txt"),
+            PathBuf::from("~other/words.txt")
+        );
+        assert_eq!(expand_home("/words.txt"), PathBuf::from("/words.txt"));
+
+        let mut config = Config::default();
+        config.set("keyfile", "~/vault.key").unwrap();
+        assert_eq!(config.keyfile, Some(home.join("vault.key")));
+        config.set("wordlist", "~/eff_large_wordlist.txt").unwrap();
+        assert_eq!(config.wordlist, Some(home.join("eff_large_wordlist.txt")));
+        config.set("wordlist", "").unwrap();
+        assert_eq!(config.wordlist, None);
+    }
+}
@@ -1,76 +1,291 @@
+use iced::advanced::text::highlighter;
 use iced::keyboard::key;
 use iced::widget::operation::{focus, focus_next, focus_previous};
 use iced::widget::{
-    Space, button, checkbox, column, container, row, text, text_editor, text_input,
+    Space, button, checkbox, column, container, mouse_area, radio, row, scrollable, slider, text,
+    text_editor, text_input, tooltip,
 };
-use iced::{Element, Length, Subscription};
+use iced::{Element, Font, Length, Subscription, font, window};
 use iced::{Event, Task, event, keyboard};
 
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
 use std::path::PathBuf;
+use std::rc::Rc;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use crate::VERSION;
-use crate::store::{load_pdpw_file, store_pdpw_file};
+use crate::clipboard;
+use crate::config::Config;
+use crate::entry::{Document, Entry, FieldKind};
+use crate::generate::{self, Generated, Mode, generate, load_wordlist};
+use crate::kdf::vault_work_factor;
+use crate::lock::{LockOwner, VaultLock, lock_vault};
+use crate::merge::{Choice, Hunk, conflicts, merge, resolve};
+use crate::recipients::{
+    Recipient, RecipientChanges, identity_recipients, load_recipients, recipient_changes,
+    remember_recipients, store_recipients,
+};
+use crate::search::{self, Match, Matcher, Query, next_match};
+use crate::store::{
+    Backup, Keys, list_backups, load_backup, load_pdpw_file, parse_recipient, seal,
+    store_pdpw_file, unseal, vault_digest,
+};
+use crate::totp::Totp;
+use crate::watch::watch_file;
 
 #[derive(Debug, PartialEq)]
 enum ModalState {
     Search,
     Pin,
     UpdatePin,
+    Restore,
+    Recipients,
+    Merge,
+    Generate,
+    /// Asking about unsaved changes before closing, over the dialog that was
+    /// open
+    Quit(Box<ModalState>),
     None,
 }
+
+/// State of the "mask secrets" view, in which password values are hidden.
+#[derive(Debug, Default)]
+struct Masked {
+    hovered: Option<usize>,
+    /// Lines revealed by double clicking them
+    revealed: HashSet<usize>,
+}
+
+/// The vault before a replace, to undo it while the text is still the one the
+/// replace left.
+#[derive(Debug)]
+struct Replaced {
+    before: String,
+    cursor: text_editor::Cursor,
+    after: String,
+}
+
+/// The vault contents as last read from or written to disk.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Snapshot {
+    contents: Arc<String>,
+    digest: Option<[u8; 32]>,
+}
+
+/// Search matches cached for a query and revision of the text.
+struct SearchMatches {
+    query: Query,
+    revision: u64,
+    matches: Rc<Result<Vec<Match>, String>>,
+}
+
+/// Unsaved changes of a locked editor and the version they are based on,
+/// both encrypted like the vault.
+#[derive(Debug)]
+pub(crate) struct Sealed {
+    contents: Vec<u8>,
+    base: Vec<u8>,
+    digest: Option<[u8; 32]>,
+}
 pub(crate) struct Editor {
+    backups: Vec<Backup>,
+    backup_preview: Option<(usize, Arc<String>)>,
+    /// Digest of the vault file as last seen by the watcher
+    disk_digest: Option<[u8; 32]>,
+    config: Config,
     content: text_editor::Content,
+    /// The entries of `content`, parsed again on every edit
+    document: Document,
+    /// Text we put on the clipboard and when to clear it again
+    copied: Option<(String, Instant)>,
+    /// Filter of the entry sidebar, `None` while it is closed
+    entry_filter: Option<String>,
     error: Option<String>,
+    generated: Option<Generated>,
+    generator: generate::Options,
     is_dirty: bool,
     is_loading: bool,
+    /// A save is running, its changes are not on disk yet
+    is_saving: bool,
+    keyfile: Option<PathBuf>,
+    last_activity: Instant,
+    lock: Option<Arc<VaultLock>>,
+    /// Shown read-only with secrets masked while `Some`
+    masked: Option<Masked>,
+    lock_owner: Option<LockOwner>,
+    merge: Vec<Hunk>,
+    merge_choices: Vec<Option<Choice>>,
     modal: ModalState,
     pdpw_file: PathBuf,
     pin: String,
     old_pin: String,
     new_pin: String,
-    search_string: String,
-    case_sensitive: bool,
+    new_keyfile: Option<PathBuf>,
+    on_disk: Snapshot,
+    own_keys: Vec<String>,
+    pending_removal: Option<usize>,
+    /// Keys before a pin change, until the vault got saved with the new ones
+    previous_keys: Option<Box<Keys>>,
+    recipients: Vec<Recipient>,
+    /// Changes to the recipients file since this user last saved the vault,
+    /// which have to be confirmed before saving to them
+    recipient_changes: RecipientChanges,
+    recipient_key: String,
+    recipient_label: String,
+    /// Why the recipients file couldn't be loaded, saving would drop them
+    recipients_error: Option<String>,
+    search: Query,
+    replacement: String,
+    replaced: Vec<Replaced>,
+    /// Edits of `content` so far, to tell whether `search_matches` is current
+    revision: u64,
+    /// Matches of `search` in `content` at a revision, so the view doesn't
+    /// search the whole text on every redraw
+    search_matches: RefCell<Option<SearchMatches>>,
+    /// Unsaved changes of a locked editor, encrypted like the vault
+    sealed: Option<Arc<Sealed>>,
+    /// Contents, merge base and pin kept while the unsaved changes get sealed
+    sealing: Option<(String, Snapshot, String)>,
+    theirs: Option<Snapshot>,
+    weak_work_factor: Option<u8>,
+    wordlist: Option<Arc<Vec<String>>>,
 }
 
 #[derive(Debug, Clone)]
 pub(crate) enum Message {
     ActionPerformed(text_editor::Action),
-    ContentLoaded(Result<Arc<String>, Error>),
+    AddRecipient,
+    ApplyMerge,
+    BackupLoaded(usize, Result<Arc<String>, Error>),
+    BackupsListed(Result<Vec<Backup>, Error>),
+    BreakLock,
+    CancelQuit,
+    ClearKeyfile,
+    CloseRequested,
+    ConfirmRecipients,
+    ContentLoaded(Result<Snapshot, Error>),
+    Copy,
+    CopyField(FieldKind),
+    CopyTotp,
+    CopyValue,
+    Cut,
+    DiskChecked(Option<[u8; 32]>),
+    EntryFilter(String),
     Event(Event),
-    FileSaved(Result<PathBuf, Error>),
+    FileChanged,
+    FileSaved(Result<Snapshot, Error>),
+    GeneratorOptions(generate::Options),
     HideModal,
+    HoverLine(Option<usize>),
+    InsertGenerated,
+    JumpToEntry(usize),
+    Lock,
+    KeyfilePicked(Option<PathBuf>),
     LoadPdpwFile,
+    LockChecked(Result<Result<Arc<VaultLock>, LockOwner>, Error>),
+    MergeChoice(usize, Choice),
+    MoveToLine(usize),
     NewPinInput(String),
     NoHideModal,
     OldPinInput(String),
+    OpenGenerate,
+    OpenRecipients,
+    OpenRestore,
     OpenSearch,
     OpenSetPin,
+    PickKeyfile,
     PinInput(String),
+    PreviewBackup(usize),
+    Quit,
+    RecipientKeyInput(String),
+    RecipientLabelInput(String),
+    RecipientsLoaded(Result<(Vec<Recipient>, RecipientChanges), Error>),
+    Regenerate,
+    Reencrypt,
+    Reload,
+    RemoveRecipient(usize),
+    Replace,
+    ReplaceAll,
+    ReplaceString(String),
+    RestoreBackup,
+    RevealLine(usize),
+    SaveAndQuit,
     SavePdpwFile,
     Search,
     SearchString(String),
+    Sealed(Result<Arc<Sealed>, Error>),
     SetNewPassword,
     SetPdpwPath(PathBuf),
+    Tick(Instant),
     ToggleCaseSensitive(bool),
+    ToggleEntries,
+    ToggleMask,
+    ToggleRegex(bool),
+    ToggleWholeWord(bool),
+    Unsealed(Result<(Arc<String>, Snapshot), Error>),
+    WordlistLoaded(Result<Arc<Vec<String>>, Error>),
+    WorkFactorChecked(Option<u8>),
 }
 
 impl Editor {
-    pub(crate) fn new(pdpw_file_path: &std::sync::Arc<String>) -> (Self, Task<Message>) {
+    pub(crate) fn new(
+        pdpw_file_path: &std::sync::Arc<String>,
+        config: Config,
+        pin: Option<String>,
+    ) -> (Self, Task<Message>) {
+        let own_keys = identity_recipients(&config.identities);
+        let keyfile = config.keyfile.clone();
         (
             Self {
+                backups: Vec::new(),
+                backup_preview: None,
+                disk_digest: None,
+                config,
                 content: text_editor::Content::new(),
+                document: Document::default(),
+                copied: None,
+                entry_filter: None,
                 error: None,
+                generated: None,
+                generator: generate::Options::default(),
                 is_dirty: false,
                 is_loading: true,
+                is_saving: false,
+                keyfile,
+                last_activity: Instant::now(),
+                lock: None,
+                lock_owner: None,
+                masked: None,
+                merge: Vec::new(),
+                merge_choices: Vec::new(),
                 modal: ModalState::Pin,
                 pdpw_file: PathBuf::new(),
-                pin: String::new(),
+                pin: pin.unwrap_or_default(),
                 old_pin: String::new(),
                 new_pin: String::new(),
-                search_string: String::new(),
-                case_sensitive: false,
+                new_keyfile: None,
+                on_disk: Snapshot::default(),
+                own_keys,
+                pending_removal: None,
+                previous_keys: None,
+                recipients: Vec::new(),
+                recipient_changes: RecipientChanges::default(),
+                recipient_key: String::new(),
+                recipient_label: String::new(),
+                recipients_error: None,
+                search: Query::default(),
+                replacement: String::new(),
+                replaced: Vec::new(),
+                revision: 0,
+                search_matches: RefCell::default(),
+                sealed: None,
+                sealing: None,
+                theirs: None,
+                weak_work_factor: None,
+                wordlist: None,
             },
             Task::perform(
                 set_pdpw_path(PathBuf::from((*pdpw_file_path).as_str())),
@@ -79,47 +294,388 @@ impl Editor {
         )
     }
 
+    /// Replaces the text, keeping the parsed entries in sync.
+    fn set_content(&mut self, text: &str) {
+        self.content = text_editor::Content::with_text(text);
+        self.parse_document();
+    }
+
+    /// Parses the entries again after the text was edited.
+    fn parse_document(&mut self) {
+        self.document = Document::parse(&self.content.text());
+        self.revision += 1;
+    }
+
+    /// The matches of the search in the text, searched again only after the
+    /// query or the text changed.
+    fn search_matches(&self) -> Rc<Result<Vec<Match>, String>> {
+        let mut cached = self.search_matches.borrow_mut();
+        if let Some(cached) = cached.as_ref()
+            && cached.query == self.search
+            && cached.revision == self.revision
+        {
+            return cached.matches.clone();
+        }
+        let matches = Rc::new(
+            Matcher::new(&self.search)
+                .map(|matcher| matcher.find_all(&self.content.text()))
+                .map_err(|e| format!("{e}")),
+        );
+        *cached = Some(SearchMatches {
+            query: self.search.clone(),
+            revision: self.revision,
+            matches: matches.clone(),
+        });
+        matches
+    }
+
     fn hide_modal(&mut self) {
         self.modal = ModalState::None;
         self.error = None;
     }
 
+    fn keys(&self) -> Keys {
+        let mut keys = self.config.keys(&self.pin);
+        keys.keyfile.clone_from(&self.keyfile);
+        keys.recipients = self.recipients.iter().map(|r| r.key.clone()).collect();
+        keys.previous.clone_from(&self.previous_keys);
+        keys
+    }
+
+    fn is_own_key(&self, recipient: &Recipient) -> bool {
+        self.own_keys.iter().any(|key| recipient.matches(key))
+    }
+
     fn run_save_file(&mut self) -> Task<Message> {
-        if self.is_loading {
+        if let Some(owner) = &self.lock_owner {
+            self.error = Some(format!("Read-only, the vault is opened by {owner}!"));
+            Task::none()
+        } else if let Some(e) = &self.recipients_error {
+            self.error = Some(format!("Fix the recipients file before saving: {e}"));
+            Task::none()
+        } else if !self.recipient_changes.is_empty() {
+            self.error = Some(
+                "The recipients file changed since your last save, \
+                 confirm the changes in the Recipients dialog first!"
+                    .into(),
+            );
+            Task::none()
+        } else if self.is_loading {
             Task::none()
         } else {
             self.is_loading = true;
+            self.is_saving = true;
             self.is_dirty = false;
             Task::perform(
                 save_file(
                     self.pdpw_file.clone(),
-                    self.pin.clone(),
+                    self.keys(),
                     self.content.text(),
+                    self.config.backup_count,
+                    self.config.armor,
+                    self.recipients.clone(),
+                    self.on_disk.digest,
                 ),
                 Message::FileSaved,
             )
         }
     }
 
+    /// Puts `text` on the clipboard and schedules clearing it.
+    fn copy_to_clipboard(&mut self, text: String) {
+        if let Err(e) = clipboard::copy(&text) {
+            self.error = Some(format!("{e:#}"));
+            return;
+        }
+        self.copied = self
+            .config
+            .clear_clipboard_after
+            .map(|after| (text, Instant::now() + after));
+    }
+
+    fn regenerate(&mut self) {
+        match generate(&self.generator, self.wordlist.as_deref().map(Vec::as_slice)) {
+            Ok(generated) => {
+                self.generated = Some(generated);
+                self.error = None;
+            }
+            Err(e) => {
+                self.generated = None;
+                self.error = Some(format!("{e:#}"));
+            }
+        }
+    }
+
+    /// Copies the value of a field, not the key.
+    fn copy_field(&mut self, pick: impl FnOnce(&Document, usize) -> Option<String>) {
+        let line = self.content.cursor().position.line;
+        let value = pick(&self.document, line);
+        if let Some(value) = value {
+            self.copy_to_clipboard(value);
+        }
+    }
+
+    /// Whether the cursor is in an entry with a TOTP secret, whose code is
+    /// shown and needs refreshing.
+    fn shows_totp(&self) -> bool {
+        self.document
+            .entry_at(self.content.cursor().position.line)
+            .and_then(Entry::totp)
+            .is_some()
+    }
+
+    fn clear_clipboard(&mut self) {
+        if let Some((copied, _)) = self.copied.take()
+            && let Err(e) = clipboard::clear_if_unchanged(&copied)
+        {
+            self.error = Some(format!("{e:#}"));
+        }
+    }
+
+    /// Drops the plain text and the pin and asks for the pin again. Unsaved
+    /// changes, including those of a running save that may still fail, are
+    /// kept encrypted until the vault is unlocked.
+    fn lock(&mut self) -> Task<Message> {
+        if self.is_locked() {
+            return Task::none();
+        }
+        let sealing = (self.is_dirty || self.is_saving).then(|| {
+            let keys = self.keys();
+            let contents = self.content.text();
+            let base = self.on_disk.clone();
+            self.sealing = Some((
+                contents.clone(),
+                base.clone(),
+                std::mem::take(&mut self.pin),
+            ));
+            Task::perform(
+                seal_content(self.pdpw_file.clone(), keys, contents, base),
+                Message::Sealed,
+            )
+        });
+        self.on_disk = Snapshot::default();
+        self.content = text_editor::Content::new();
+        self.document = Document::default();
+        self.revision += 1;
+        self.clear_clipboard();
+        self.pin.clear();
+        self.old_pin.clear();
+        self.previous_keys = None;
+        self.new_pin.clear();
+        self.backup_preview = None;
+        self.merge.clear();
+        self.merge_choices.clear();
+        self.theirs = None;
+        self.generated = None;
+        self.replaced.clear();
+        if let Some(masked) = &mut self.masked {
+            *masked = Masked::default();
+        }
+        self.is_dirty = false;
+        self.error = None;
+        self.modal = ModalState::Pin;
+        Task::batch([sealing.unwrap_or_else(Task::none), focus("pin-input")])
+    }
+
+    /// Whether closing now would lose changes, including those of a running
+    /// save and those kept sealed while the vault is locked.
+    fn has_unsaved_changes(&self) -> bool {
+        self.is_dirty || self.is_saving || self.sealed.is_some() || self.sealing.is_some()
+    }
+
+    /// Whether the vault is locked and can't be saved.
+    fn is_locked(&self) -> bool {
+        match &self.modal {
+            ModalState::Pin => true,
+            ModalState::Quit(previous) => **previous == ModalState::Pin,
+            _ => false,
+        }
+    }
+
+    /// The vault changed on disk since it was loaded: merge our changes into
+    /// the new version, asking about conflicting lines.
+    fn start_merge(&mut self, theirs: Snapshot) -> Task<Message> {
+        self.is_dirty = true;
+        let hunks = merge(
+            &self.on_disk.contents,
+            &self.content.text(),
+            &theirs.contents,
+        );
+        let conflicts = conflicts(&hunks);
+        if conflicts == 0 {
+            self.set_content(&resolve(&hunks, &[]));
+            self.on_disk = theirs;
+            return self.run_save_file();
+        }
+        self.merge = hunks;
+        self.merge_choices = vec![None; conflicts];
+        self.theirs = Some(theirs);
+        self.modal = ModalState::Merge;
+        Task::none()
+    }
+
     #[allow(clippy::too_many_lines)]
     pub(crate) fn update(&mut self, message: Message) -> Task<Message> {
+        if let Message::Event(Event::Keyboard(_) | Event::Mouse(_) | Event::Touch(_)) = &message {
+            self.last_activity = Instant::now();
+        }
         match message {
             Message::ActionPerformed(action) => {
-                if self.modal == ModalState::None {
-                    self.is_dirty = self.is_dirty || action.is_edit();
+                if self.modal == ModalState::None
+                    && !(self.lock_owner.is_some() && action.is_edit())
+                {
+                    let is_edit = action.is_edit();
+                    self.is_dirty = self.is_dirty || is_edit;
                     self.content.perform(action);
+                    if is_edit {
+                        self.parse_document();
+                    }
                 }
                 Task::none()
             }
-            Message::ContentLoaded(result) => {
+            Message::AddRecipient => {
+                if self.lock_owner.is_some() {
+                    self.error = Some("The vault is opened read-only!".into());
+                    return Task::none();
+                }
+                let key = self.recipient_key.trim().to_string();
+                if let Err(e) = parse_recipient(&key) {
+                    self.error = Some(format!("{e}"));
+                } else if self.recipients.iter().any(|r| r.matches(&key)) {
+                    self.error = Some("This recipient is already on the list!".into());
+                } else {
+                    self.recipients.push(Recipient {
+                        key,
+                        label: self.recipient_label.trim().to_string(),
+                    });
+                    self.recipient_key.clear();
+                    self.recipient_label.clear();
+                    self.pending_removal = None;
+                    self.error = None;
+                    self.is_dirty = true;
+                }
+                Task::none()
+            }
+            Message::RecipientKeyInput(key) => {
+                self.recipient_key = key;
+                Task::none()
+            }
+            Message::RecipientLabelInput(label) => {
+                self.recipient_label = label;
+                Task::none()
+            }
+            Message::RecipientsLoaded(result) => {
+                self.recipients_error = None;
+                match result {
+                    Ok((recipients, changes)) => {
+                        self.recipients = recipients;
+                        if !changes.is_empty() {
+                            self.error =
+                                Some("The recipients file changed since your last save!".into());
+                        }
+                        self.recipient_changes = changes;
+                        // recipients given on the command line get persisted on the next save
+                        for key in &self.config.recipients {
+                            if !self.recipients.iter().any(|r| r.matches(key)) {
+                                self.recipients.push(Recipient {
+                                    key: key.clone(),
+                                    label: "command line".into(),
+                                });
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        self.error = Some(format!("{e}"));
+                        self.recipients_error = Some(format!("{e}"));
+                    }
+                }
+                Task::none()
+            }
+            Message::ConfirmRecipients => {
+                self.recipient_changes = RecipientChanges::default();
+                self.error = None;
+                Task::none()
+            }
+            Message::RemoveRecipient(index) => {
+                if self.lock_owner.is_some() {
+                    self.error = Some("The vault is opened read-only!".into());
+                    return Task::none();
+                }
+                let Some(recipient) = self.recipients.get(index) else {
+                    return Task::none();
+                };
+                if self.is_own_key(recipient) && self.pending_removal != Some(index) {
+                    // ask for confirmation by pressing remove a second time
+                    self.pending_removal = Some(index);
+                } else {
+                    self.recipients.remove(index);
+                    self.pending_removal = None;
+                    self.is_dirty = true;
+                }
+                Task::none()
+            }
+            Message::BackupsListed(result) => {
+                match result {
+                    Ok(backups) => self.backups = backups,
+                    Err(e) => self.error = Some(format!("{e}")),
+                }
+                Task::none()
+            }
+            Message::BackupLoaded(index, result) => {
                 match result {
                     Ok(contents) => {
+                        self.error = None;
+                        self.backup_preview = Some((index, contents));
+                    }
+                    Err(e) => {
+                        self.backup_preview = None;
+                        self.error = Some(format!("{e}"));
+                    }
+                }
+                Task::none()
+            }
+            Message::ContentLoaded(result) => {
+                self.is_loading = false;
+                let mut tasks = vec![focus_next()];
+                match result {
+                    Ok(snapshot) => {
                         self.hide_modal();
-                        self.content = text_editor::Content::with_text(&contents);
+                        self.set_content(&snapshot.contents);
+                        self.disk_digest = snapshot.digest;
+                        self.on_disk = snapshot;
+                        self.is_dirty = false;
+                        self.last_activity = Instant::now();
+                        if let Some((contents, base, _)) = self.sealing.take() {
+                            // unlocked before the changes got sealed
+                            self.set_content(&contents);
+                            self.on_disk = base;
+                            self.is_dirty = true;
+                        } else if let Some(sealed) = self.sealed.take() {
+                            tasks.push(Task::perform(
+                                unseal_content(self.pdpw_file.clone(), sealed, self.keys()),
+                                Message::Unsealed,
+                            ));
+                        }
+                        // a target time is only measured on the first save
+                        if self.config.kdf.known_work_factor().is_some() {
+                            tasks.push(Task::perform(
+                                check_work_factor(self.pdpw_file.clone()),
+                                Message::WorkFactorChecked,
+                            ));
+                        }
                     }
                     Err(error) => self.error = Some(format!("{error:?}")),
                 }
-                focus_next()
+                Task::batch(tasks)
+            }
+            Message::WorkFactorChecked(work_factor) => {
+                self.weak_work_factor = work_factor.filter(|log_n| {
+                    self.config
+                        .kdf
+                        .known_work_factor()
+                        .is_some_and(|p| *log_n < p)
+                });
+                Task::none()
             }
             Message::Event(event) => match event {
                 Event::Keyboard(keyboard::Event::KeyPressed {
@@ -137,67 +693,136 @@ impl Editor {
                     key: keyboard::Key::Character(c),
                     modifiers,
                     ..
-                }) if modifiers.command() && self.modal != ModalState::Pin => match c.as_str() {
+                }) if modifiers.command() && !self.is_locked() => match c.as_str() {
                     "s" => self.run_save_file(),
+                    "l" => self.lock(),
                     "f" => {
                         self.modal = ModalState::Search;
                         focus("search-input")
                     }
+                    "e" => self.update(Message::ToggleEntries),
+                    "g" => self.update(Message::OpenGenerate),
+                    "z" => {
+                        self.undo_replace();
+                        Task::none()
+                    }
+                    "C" | "c" if modifiers.shift() => self.update(Message::CopyValue),
+                    "b" => self.update(Message::CopyField(FieldKind::Username)),
+                    "u" => self.update(Message::CopyField(FieldKind::Url)),
                     _ => Task::none(),
                 },
                 Event::Keyboard(keyboard::Event::KeyPressed {
                     key: keyboard::Key::Named(key::Named::F3),
+                    modifiers,
                     ..
                 }) => {
                     if self.modal == ModalState::None {
-                        self.execute_search(true);
+                        self.execute_search(modifiers.shift(), true);
                     }
                     Task::none()
                 }
                 Event::Keyboard(keyboard::Event::KeyPressed {
                     key: keyboard::Key::Named(key::Named::Escape | key::Named::Enter),
                     ..
-                }) => {
-                    if self.modal != ModalState::Pin {
+                }) => match self.modal {
+                    ModalState::Pin => Task::none(),
+                    ModalState::Quit(_) => self.update(Message::CancelQuit),
+                    _ => {
                         self.hide_modal();
+                        Task::none()
                     }
-                    Task::none()
-                }
+                },
                 _ => Task::none(),
             },
             Message::LoadPdpwFile => {
                 self.is_loading = false;
-                if self.pin.is_empty() {
+                if self.pin.is_empty() && self.config.identities.is_empty() {
                     Task::none()
                 } else {
                     Task::perform(
-                        load_content(self.pdpw_file.clone(), self.pin.clone()),
+                        load_content(self.pdpw_file.clone(), self.keys()),
                         Message::ContentLoaded,
                     )
                 }
             }
-            Message::SavePdpwFile => self.run_save_file(),
+            Message::SavePdpwFile | Message::Reencrypt | Message::SaveAndQuit => {
+                self.run_save_file()
+            }
             Message::SetNewPassword => {
-                if self.pin == self.old_pin {
+                if self.pin != self.old_pin {
+                    self.error = Some("Old password does not match!".into());
+                    Task::none()
+                } else if self.new_keyfile.is_some() && self.new_pin.is_empty() {
+                    self.error = Some("A keyfile needs a password as well!".into());
+                    Task::none()
+                } else {
+                    if self.previous_keys.is_none() {
+                        self.previous_keys = Some(Box::new(self.keys()));
+                    }
                     self.pin = self.new_pin.clone();
+                    self.keyfile = self.new_keyfile.take();
                     self.hide_modal();
                     self.run_save_file()
-                } else {
-                    self.error = Some("Old password does not match!".into());
-                    Task::none()
                 }
             }
             Message::FileSaved(result) => {
                 self.is_loading = false;
+                self.is_saving = false;
                 match result {
-                    Ok(path) => self.pdpw_file = path,
+                    Ok(snapshot) => {
+                        self.disk_digest = snapshot.digest;
+                        self.on_disk = if self.is_locked() {
+                            // saved just before locking, keep no plain text
+                            Snapshot {
+                                digest: snapshot.digest,
+                                ..Snapshot::default()
+                            }
+                        } else {
+                            snapshot
+                        };
+                        self.error = None;
+                        self.weak_work_factor = None;
+                        self.previous_keys = None;
+                        if matches!(self.modal, ModalState::Quit(_)) && !self.has_unsaved_changes()
+                        {
+                            return iced::exit();
+                        }
+                    }
+                    // the changes are sealed, merge them once unlocked
+                    Err(Error::Changed(_)) if self.is_locked() => {}
+                    Err(Error::Changed(theirs)) => return self.start_merge(theirs),
                     Err(e) => {
-                        self.is_dirty = true;
-                        self.error = Some(format!("{e:?}"));
+                        self.is_dirty = !self.is_locked();
+                        self.error = Some(format!("{e}"));
                     }
                 }
                 Task::none()
             }
+            Message::CloseRequested => {
+                if !self.has_unsaved_changes() {
+                    return iced::exit();
+                }
+                if !matches!(self.modal, ModalState::Quit(_)) {
+                    let previous = std::mem::replace(&mut self.modal, ModalState::None);
+                    self.modal = ModalState::Quit(Box::new(previous));
+                    self.error = None;
+                }
+                Task::none()
+            }
+            Message::Quit => iced::exit(),
+            Message::CancelQuit => {
+                if let ModalState::Quit(previous) =
+                    std::mem::replace(&mut self.modal, ModalState::None)
+                {
+                    self.modal = *previous;
+                }
+                self.error = None;
+                if self.modal == ModalState::Pin {
+                    focus("pin-input")
+                } else {
+                    Task::none()
+                }
+            }
             Message::HideModal => {
                 self.modal = ModalState::None;
                 Task::none()
@@ -210,6 +835,207 @@ impl Editor {
                 self.pin = pin;
                 Task::none()
             }
+            Message::PickKeyfile => Task::perform(pick_keyfile(), Message::KeyfilePicked),
+            Message::KeyfilePicked(Some(path)) => {
+                if self.modal == ModalState::UpdatePin {
+                    self.new_keyfile = Some(path);
+                } else {
+                    self.keyfile = Some(path);
+                }
+                Task::none()
+            }
+            Message::KeyfilePicked(None) => Task::none(),
+            Message::ClearKeyfile => {
+                if self.modal == ModalState::UpdatePin {
+                    self.new_keyfile = None;
+                } else {
+                    self.keyfile = None;
+                }
+                Task::none()
+            }
+            Message::ToggleEntries => {
+                if self.entry_filter.take().is_some() {
+                    Task::none()
+                } else {
+                    self.entry_filter = Some(String::new());
+                    focus("entry-filter")
+                }
+            }
+            Message::EntryFilter(filter) => {
+                self.entry_filter = Some(filter);
+                Task::none()
+            }
+            Message::JumpToEntry(line) => {
+                self.content.move_to(text_editor::Cursor {
+                    position: text_editor::Position { line, column: 0 },
+                    selection: None,
+                });
+                focus("editor")
+            }
+            Message::Lock => self.lock(),
+            Message::Copy => {
+                if let Some(selection) = self.content.selection() {
+                    self.copy_to_clipboard(selection);
+                }
+                Task::none()
+            }
+            Message::CopyValue => {
+                self.copy_field(|document, line| {
+                    let field = document.entry_at(line)?.field_at(line)?;
+                    Some(field.value.clone())
+                });
+                Task::none()
+            }
+            Message::CopyField(kind) => {
+                self.copy_field(|document, line| {
+                    let field = document.entry_at(line)?.field(kind)?;
+                    Some(field.value.clone())
+                });
+                Task::none()
+            }
+            Message::CopyTotp => {
+                self.copy_field(|document, line| {
+                    let totp = Totp::parse(document.entry_at(line)?.totp()?).ok()?;
+                    Some(totp.now().0)
+                });
+                Task::none()
+            }
+            Message::ToggleMask => {
+                self.masked = match self.masked {
+                    Some(_) => None,
+                    None => Some(Masked::default()),
+                };
+                Task::none()
+            }
+            Message::HoverLine(line) => {
+                if let Some(masked) = &mut self.masked {
+                    masked.hovered = line;
+                }
+                Task::none()
+            }
+            Message::RevealLine(line) => {
+                if let Some(masked) = &mut self.masked
+                    && !masked.revealed.remove(&line)
+                {
+                    masked.revealed.insert(line);
+                }
+                Task::none()
+            }
+            Message::MoveToLine(line) => {
+                self.content.move_to(text_editor::Cursor {
+                    position: text_editor::Position { line, column: 0 },
+                    selection: None,
+                });
+                Task::none()
+            }
+            Message::Cut => {
+                if let Some(selection) = self.content.selection() {
+                    self.copy_to_clipboard(selection);
+                    return self.update(Message::ActionPerformed(text_editor::Action::Edit(
+                        text_editor::Edit::Delete,
+                    )));
+                }
+                Task::none()
+            }
+            Message::Tick(now) => {
+                if self.copied.as_ref().is_some_and(|(_, at)| now >= *at) {
+                    self.clear_clipboard();
+                }
+                match self.config.lock_after {
+                    Some(idle) if now.duration_since(self.last_activity) >= idle => self.lock(),
+                    _ => Task::none(),
+                }
+            }
+            Message::Sealed(result) => {
+                match (result, self.sealing.take()) {
+                    (Ok(sealed), Some(_)) => self.sealed = Some(sealed),
+                    (Err(e), Some((contents, base, pin))) => {
+                        // stay unlocked rather than lose the changes
+                        self.set_content(&contents);
+                        self.on_disk = base;
+                        self.pin = pin;
+                        self.is_dirty = true;
+                        self.modal = ModalState::None;
+                        self.last_activity = Instant::now();
+                        self.error = Some(format!("Couldn't lock the vault: {e}"));
+                    }
+                    // unlocked meanwhile, the changes are already restored
+                    (_, None) => {}
+                }
+                Task::none()
+            }
+            Message::Unsealed(result) => {
+                match result {
+                    Ok((contents, base)) => {
+                        self.set_content(&contents);
+                        self.on_disk = base;
+                        self.is_dirty = true;
+                    }
+                    Err(e) => self.error = Some(format!("Couldn't restore unsaved changes: {e}")),
+                }
+                Task::none()
+            }
+            Message::FileChanged => {
+                if self.is_loading {
+                    // our own save, or the initial load
+                    Task::none()
+                } else {
+                    Task::perform(check_disk(self.pdpw_file.clone()), Message::DiskChecked)
+                }
+            }
+            Message::DiskChecked(digest) => {
+                if !self.is_loading {
+                    self.disk_digest = digest;
+                }
+                Task::none()
+            }
+            Message::Reload => {
+                self.is_loading = true;
+                Task::perform(
+                    load_content(self.pdpw_file.clone(), self.keys()),
+                    Message::ContentLoaded,
+                )
+            }
+            Message::MergeChoice(index, choice) => {
+                if let Some(slot) = self.merge_choices.get_mut(index) {
+                    *slot = Some(choice);
+                }
+                Task::none()
+            }
+            Message::ApplyMerge => {
+                let Some(choices) = self
+                    .merge_choices
+                    .iter()
+                    .copied()
+                    .collect::<Option<Vec<_>>>()
+                else {
+                    self.error = Some("Pick a side for every conflict!".into());
+                    return Task::none();
+                };
+                self.set_content(&resolve(&self.merge, &choices));
+                if let Some(theirs) = self.theirs.take() {
+                    self.on_disk = theirs;
+                }
+                self.merge.clear();
+                self.merge_choices.clear();
+                self.hide_modal();
+                self.run_save_file()
+            }
+            Message::BreakLock => Task::perform(
+                lock_file(self.pdpw_file.clone(), true),
+                Message::LockChecked,
+            ),
+            Message::LockChecked(result) => {
+                match result {
+                    Ok(Ok(lock)) => {
+                        self.lock = Some(lock);
+                        self.lock_owner = None;
+                    }
+                    Ok(Err(owner)) => self.lock_owner = Some(owner),
+                    Err(e) => self.error = Some(format!("{e}")),
+                }
+                Task::none()
+            }
             Message::OldPinInput(pin) => {
                 self.old_pin = pin;
                 Task::none()
@@ -218,104 +1044,427 @@ impl Editor {
                 self.new_pin = pin;
                 Task::none()
             }
+            Message::OpenRecipients => {
+                self.modal = ModalState::Recipients;
+                self.pending_removal = None;
+                // try again once the broken file got fixed
+                let reload = if self.recipients_error.is_some() {
+                    Task::perform(
+                        load_vault_recipients(self.pdpw_file.clone()),
+                        Message::RecipientsLoaded,
+                    )
+                } else {
+                    Task::none()
+                };
+                Task::batch([reload, focus("recipient-key-input")])
+            }
+            Message::OpenRestore => {
+                self.modal = ModalState::Restore;
+                self.backups.clear();
+                self.backup_preview = None;
+                Task::perform(
+                    list_vault_backups(self.pdpw_file.clone()),
+                    Message::BackupsListed,
+                )
+            }
+            Message::PreviewBackup(index) => match self.backups.get(index) {
+                Some(backup) => Task::perform(
+                    load_backup_content(self.pdpw_file.clone(), backup.path.clone(), self.keys()),
+                    move |result| Message::BackupLoaded(index, result),
+                ),
+                None => Task::none(),
+            },
+            Message::RestoreBackup => {
+                if self.lock_owner.is_some() {
+                    self.error = Some("The vault is opened read-only!".into());
+                    return Task::none();
+                }
+                if let Some((_, contents)) = self.backup_preview.take() {
+                    self.set_content(&contents);
+                    self.is_dirty = true;
+                    self.hide_modal();
+                }
+                Task::none()
+            }
+            Message::OpenGenerate => {
+                self.modal = ModalState::Generate;
+                match &self.config.wordlist {
+                    Some(path) if self.wordlist.is_none() => {
+                        Task::perform(read_wordlist(path.clone()), Message::WordlistLoaded)
+                    }
+                    _ => {
+                        self.regenerate();
+                        Task::none()
+                    }
+                }
+            }
+            Message::WordlistLoaded(result) => {
+                match result {
+                    Ok(wordlist) => {
+                        self.wordlist = Some(wordlist);
+                        self.regenerate();
+                    }
+                    Err(e) => self.error = Some(format!("{e}")),
+                }
+                Task::none()
+            }
+            Message::GeneratorOptions(options) => {
+                self.generator = options;
+                self.regenerate();
+                Task::none()
+            }
+            Message::Regenerate => {
+                self.regenerate();
+                Task::none()
+            }
+            Message::InsertGenerated => {
+                if self.lock_owner.is_some() {
+                    self.error = Some("The vault is opened read-only!".into());
+                    return Task::none();
+                }
+                let Some(generated) = self.generated.take() else {
+                    return Task::none();
+                };
+                self.content
+                    .perform(text_editor::Action::Edit(text_editor::Edit::Paste(
+                        Arc::new(generated.secret),
+                    )));
+                self.parse_document();
+                self.is_dirty = true;
+                self.hide_modal();
+                focus("editor")
+            }
             Message::OpenSearch => {
                 self.modal = ModalState::Search;
                 focus("search-input")
             }
             Message::OpenSetPin => {
                 self.modal = ModalState::UpdatePin;
+                self.new_keyfile.clone_from(&self.keyfile);
                 focus("old-pin-input")
             }
             Message::Search => {
                 self.hide_modal();
-                self.execute_search(false);
+                self.execute_search(false, false);
                 focus_next()
             }
-            Message::SearchString(search_string) => {
-                self.search_string = search_string;
+            Message::SearchString(pattern) => {
+                self.search.pattern = pattern;
+                Task::none()
+            }
+            Message::ReplaceString(replacement) => {
+                self.replacement = replacement;
+                Task::none()
+            }
+            Message::Replace => {
+                self.replace(false);
+                Task::none()
+            }
+            Message::ReplaceAll => {
+                self.replace(true);
                 Task::none()
             }
             Message::ToggleCaseSensitive(is_checked) => {
-                self.case_sensitive = is_checked;
+                self.search.case_sensitive = is_checked;
+                Task::none()
+            }
+            Message::ToggleRegex(is_checked) => {
+                self.search.regex = is_checked;
+                Task::none()
+            }
+            Message::ToggleWholeWord(is_checked) => {
+                self.search.whole_word = is_checked;
                 Task::none()
             }
             Message::SetPdpwPath(pdpw_file) => {
                 self.pdpw_file = pdpw_file;
-                focus("pin-input")
+                let recipients = Task::batch([
+                    Task::perform(
+                        lock_file(self.pdpw_file.clone(), self.config.break_lock),
+                        Message::LockChecked,
+                    ),
+                    Task::perform(
+                        load_vault_recipients(self.pdpw_file.clone()),
+                        Message::RecipientsLoaded,
+                    ),
+                ]);
+                if self.pin.is_empty() && self.config.identities.is_empty() {
+                    Task::batch([recipients, focus("pin-input")])
+                } else {
+                    // the pin was provided non-interactively or isn't needed
+                    Task::batch([recipients, self.update(Message::LoadPdpwFile)])
+                }
             }
         }
     }
 
-    fn execute_search(&mut self, skip_current: bool) {
-        if self.search_string.is_empty() {
-            self.error = Some("Search string cannot be empty!".into());
-            self.modal = ModalState::Search;
+    /// Selects the next match after the cursor, or the previous one before
+    /// it when searching `backwards`.
+    fn execute_search(&mut self, backwards: bool, skip_current: bool) {
+        let matches = self.search_matches();
+        let candidates = match matches.as_ref() {
+            Ok(candidates) => candidates,
+            Err(e) => {
+                self.error = Some(e.clone());
+                self.modal = ModalState::Search;
+                return;
+            }
+        };
+        let selection = selection_bounds(self.content.cursor());
+        let Some((found, wrapped)) = next_match(candidates, selection, backwards, skip_current)
+        else {
+            self.error = Some("No matches".into());
+            return;
+        };
+        self.error = wrapped.then(|| {
+            if backwards {
+                "Search wrapped around to the bottom".into()
+            } else {
+                "Search wrapped around to the top".into()
+            }
+        });
+        self.content.move_to(text_editor::Cursor {
+            position: text_editor::Position {
+                line: found.line,
+                column: found.range.end,
+            },
+            selection: Some(text_editor::Position {
+                line: found.line,
+                column: found.range.start,
+            }),
+        });
+    }
+
+    /// Replaces the selected match and selects the next one, or every match
+    /// with `all`. The changed lines are replaced in one edit, which can be
+    /// undone with Ctrl+Z.
+    fn replace(&mut self, all: bool) {
+        if self.lock_owner.is_some() {
+            self.error = Some("The vault is opened read-only!".into());
             return;
         }
-        // simple exact search
-        let (text, search_string) = if self.case_sensitive {
-            (self.content.text(), self.search_string.clone())
-        } else {
-            (
-                self.content.text().to_lowercase(),
-                self.search_string.to_lowercase(),
-            )
+        let matcher = match Matcher::new(&self.search) {
+            Ok(matcher) => matcher,
+            Err(e) => {
+                self.error = Some(format!("{e}"));
+                return;
+            }
         };
         let cursor = self.content.cursor();
-        let (cursor_line, cursor_offset) = (cursor.position.line, cursor.position.column);
-        for (line_number, line) in text.lines().enumerate() {
-            if line_number >= cursor_line
-                && let Some(mut offset) = line.find(search_string.as_str())
-            {
-                if line_number == cursor_line
-                    && (offset < cursor_offset || (offset == cursor_offset && skip_current))
-                {
-                    let updated_search_offset = cursor_offset + 1;
-                    if updated_search_offset < line.len()
-                        && let Some(delta_offset) =
-                            line[updated_search_offset..].find(search_string.as_str())
-                    {
-                        offset = updated_search_offset + delta_offset;
-                    } else {
-                        continue;
-                    }
+        let (from, to) = selection_bounds(cursor);
+        let (first, last, replaced) = if all {
+            let mut changed = vec![];
+            for (number, line) in self.content.lines().enumerate() {
+                if let Some((replaced, _)) = matcher.replace(&line.text, &self.replacement) {
+                    changed.push((number, line.text.len(), replaced));
                 }
-                // move the cursor to the right line
-                self.content.perform(text_editor::Action::Move(
-                    text_editor::Motion::DocumentStart,
-                ));
-                for _ in 0..line_number {
-                    self.content
-                        .perform(text_editor::Action::Move(text_editor::Motion::Down));
-                }
-                // move the cursor to the right offset
-                self.content
-                    .perform(text_editor::Action::Move(text_editor::Motion::Home));
-                for _ in 0..offset {
-                    self.content
-                        .perform(text_editor::Action::Move(text_editor::Motion::Right));
-                }
-                // select the search string
-                for _ in search_string.chars() {
-                    self.content
-                        .perform(text_editor::Action::Select(text_editor::Motion::Right));
-                }
-                break;
             }
+            let (Some(first), Some(last)) = (changed.first(), changed.last()) else {
+                self.error = Some("No matches".into());
+                return;
+            };
+            let (first, last) = ((first.0, 0), (last.0, last.1));
+            // unchanged lines in between are pasted as they are
+            let mut changed = changed.into_iter().peekable();
+            let lines: Vec<String> = (first.0..=last.0)
+                .map(
+                    |number| match changed.next_if(|(line, ..)| *line == number) {
+                        Some((.., replaced)) => replaced,
+                        None => self
+                            .content
+                            .line(number)
+                            .map(|line| line.text.into_owned())
+                            .unwrap_or_default(),
+                    },
+                )
+                .collect();
+            (first, last, lines.join("\n"))
+        } else {
+            let selected = self.search_matches().iter().flatten().any(|found| {
+                (found.line, found.range.start) == from && (found.line, found.range.end) == to
+            });
+            let line = self.content.line(from.0).map(|line| line.text.into_owned());
+            let (true, Some(line)) = (selected, line) else {
+                self.execute_search(false, false);
+                return;
+            };
+            (from, to, matcher.expand(&line, from.1, &self.replacement))
+        };
+        let before = self.content.text();
+        self.content.move_to(text_editor::Cursor {
+            position: text_editor::Position {
+                line: last.0,
+                column: last.1,
+            },
+            selection: Some(text_editor::Position {
+                line: first.0,
+                column: first.1,
+            }),
+        });
+        self.content
+            .perform(text_editor::Action::Edit(text_editor::Edit::Paste(
+                Arc::new(replaced),
+            )));
+        self.parse_document();
+        self.replaced.push(Replaced {
+            before,
+            cursor,
+            after: self.content.text(),
+        });
+        self.is_dirty = true;
+        self.error = None;
+        if !all {
+            self.execute_search(false, true);
+        }
+    }
+
+    /// Undoes the last replace, as long as the text wasn't edited since.
+    fn undo_replace(&mut self) {
+        let Some(replaced) = self.replaced.pop() else {
+            return;
+        };
+        if replaced.after != self.content.text() {
+            // edited since, undoing would drop those edits
+            self.replaced.clear();
+            return;
         }
+        self.set_content(&replaced.before);
+        self.content.move_to(replaced.cursor);
+        self.is_dirty = true;
+    }
+
+    /// "n of m matches" for the search, with n only if a match is selected.
+    fn search_counter(&self) -> Option<String> {
+        if self.search.pattern.is_empty() {
+            return None;
+        }
+        let matches = self.search_matches();
+        let matches = match matches.as_ref() {
+            Ok(matches) => matches,
+            Err(e) => return Some(e.clone()),
+        };
+        let (from, to) = selection_bounds(self.content.cursor());
+        let current = matches.iter().position(|found| {
+            (found.line, found.range.start) == from && (found.line, found.range.end) == to
+        });
+        Some(match (current, matches.len()) {
+            (_, 0) => "No matches".into(),
+            (Some(index), count) => format!("{} of {count} matches", index + 1),
+            (None, count) => format!("{count} matches"),
+        })
     }
 
     #[allow(clippy::unused_self)]
     pub(crate) fn subscription(&self) -> Subscription<Message> {
-        event::listen().map(Message::Event)
+        let mut subscriptions = vec![
+            event::listen().map(Message::Event),
+            window::close_requests().map(|_| Message::CloseRequested),
+        ];
+        if !self.pdpw_file.as_os_str().is_empty() {
+            subscriptions.push(
+                Subscription::run_with(self.pdpw_file.clone(), watch_file)
+                    .map(|()| Message::FileChanged),
+            );
+        }
+        if (self.config.lock_after.is_some() && !self.is_locked())
+            || self.copied.is_some()
+            || self.shows_totp()
+        {
+            subscriptions.push(iced::time::every(Duration::from_secs(1)).map(Message::Tick));
+        }
+        Subscription::batch(subscriptions)
+    }
+
+    /// The settings of the generator dialog for the selected mode.
+    fn generator_options(&self) -> Element<'_, Message> {
+        let options = &self.generator;
+        // every widget sends the options with its own setting changed
+        let changed = move |change: fn(&mut generate::Options)| {
+            let mut options = options.clone();
+            change(&mut options);
+            Message::GeneratorOptions(options)
+        };
+        let class = |label, selected, toggle: fn(&mut generate::Options)| {
+            checkbox(selected)
+                .label(label)
+                .on_toggle(move |_| changed(toggle))
+        };
+        let mode = row![
+            radio("Password", Mode::Password, Some(options.mode), move |_| {
+                changed(|o| o.mode = Mode::Password)
+            }),
+            radio(
+                "Passphrase",
+                Mode::Passphrase,
+                Some(options.mode),
+                move |_| { changed(|o| o.mode = Mode::Passphrase) }
+            ),
+        ]
+        .spacing(20);
+        let settings = match options.mode {
+            Mode::Password => column![
+                row![
+                    text(format!("Length {}", options.length)).width(100),
+                    slider(8..=64, options.length, move |length| {
+                        let mut options = options.clone();
+                        options.length = length;
+                        Message::GeneratorOptions(options)
+                    }),
+                ],
+                class("a-z", options.lowercase, |o| o.lowercase = !o.lowercase),
+                class("A-Z", options.uppercase, |o| o.uppercase = !o.uppercase),
+                class("0-9", options.digits, |o| o.digits = !o.digits),
+                class("Symbols", options.symbols, |o| o.symbols = !o.symbols),
+                class(
+                    "No ambiguous characters (Il1O0)",
+                    options.exclude_ambiguous,
+                    |o| o.exclude_ambiguous = !o.exclude_ambiguous
+                ),
+                class("At least one of each class", options.require_each, |o| o
+                    .require_each =
+                    !o.require_each),
+            ],
+            Mode::Passphrase => column![
+                row![
+                    text(format!("Words {}", options.words)).width(100),
+                    slider(3..=12, options.words, move |words| {
+                        let mut options = options.clone();
+                        options.words = words;
+                        Message::GeneratorOptions(options)
+                    }),
+                ],
+                row![
+                    text("Separator").width(100),
+                    text_input("", &options.separator).on_input(move |separator| {
+                        Message::GeneratorOptions(generate::Options {
+                            separator,
+                            ..options.clone()
+                        })
+                    }),
+                ],
+            ],
+        };
+        column![mode, settings.spacing(5)].spacing(10).into()
     }
 
     #[allow(clippy::too_many_lines)]
     pub(crate) fn view(&'_ self) -> Element<'_, Message> {
+        let document = &self.document;
         let header = row![
             button(text("Save")).on_press(Message::SavePdpwFile),
             button(text("Search")).on_press(Message::OpenSearch),
             button(text("Set Pin")).on_press(Message::OpenSetPin),
+            button(text("Restore")).on_press(Message::OpenRestore),
+            button(text("Recipients")).on_press(Message::OpenRecipients),
+            button(text("Entries")).on_press(Message::ToggleEntries),
+            button(text("Generate")).on_press(Message::OpenGenerate),
+            button(text(if self.masked.is_some() {
+                "Show secrets"
+            } else {
+                "Mask secrets"
+            }))
+            .on_press(Message::ToggleMask),
+            button(text("Lock")).on_press(Message::Lock),
             Space::new().width(Length::Fill),
             text(format!("v{VERSION}")),
         ]
@@ -328,31 +1477,158 @@ impl Editor {
             self.pdpw_file.display().to_string()
         };
         info.push_str(if self.is_dirty { " [dirty]" } else { " [OK]" });
-        let status = row![
-            text(if info.len() > 60 {
-                format!("...{}", &info[info.len() - 40..])
+        let kdf_warning = self.weak_work_factor.map(|log_n| {
+            row![
+                text(format!(
+                    "Vault uses KDF 2^{log_n}, policy is 2^{}",
+                    self.config.kdf.known_work_factor().unwrap_or_default()
+                ))
+                .style(text::danger),
+                button(text("Re-encrypt"))
+                    .padding([0, 5])
+                    .on_press(Message::Reencrypt),
+            ]
+            .spacing(5)
+            .align_y(iced::Alignment::Center)
+        });
+        let disk_change = (self.disk_digest != self.on_disk.digest).then(|| {
+            if self.disk_digest.is_none() {
+                Element::from(text("Deleted on disk, saving writes it again").style(text::danger))
+            } else if self.is_dirty {
+                Element::from(text("Changed on disk, saving will merge").style(text::danger))
             } else {
-                info
-            }),
-            Space::new().width(Length::Fill),
-            text({
+                row![
+                    text("Changed on disk"),
+                    button(text("Reload"))
+                        .padding([0, 5])
+                        .on_press(Message::Reload),
+                ]
+                .spacing(5)
+                .align_y(iced::Alignment::Center)
+                .into()
+            }
+        });
+        // long paths and errors keep their end, the whole text is in a tooltip
+        let info = match info.char_indices().rev().nth(39) {
+            Some((start, _)) if info.chars().count() > 60 => tooltip(
+                text(format!("...{}", &info[start..])),
+                container(text(info.clone()))
+                    .max_width(600)
+                    .padding(5)
+                    .style(container::rounded_box),
+                tooltip::Position::Top,
+            )
+            .into(),
+            _ => Element::from(text(info)),
+        };
+        let status = row![info, Space::new().width(Length::Fill)]
+            .push({
+                let entries = document.entries().count();
+                (entries > 0).then(|| text(format!("{entries} entries")))
+            })
+            .push(
+                (self.modal == ModalState::None)
+                    .then(|| self.search_counter())
+                    .flatten()
+                    .map(text),
+            )
+            .push(self.copied.as_ref().map(|(_, at)| {
+                let left = at.saturating_duration_since(Instant::now());
+                text(format!("Clipboard clears in {}s", left.as_secs() + 1))
+            }))
+            .push(disk_change)
+            .push((!self.recipient_changes.is_empty()).then(|| {
+                row![
+                    text("Recipients changed").style(text::danger),
+                    button(text("Review"))
+                        .padding([0, 5])
+                        .on_press(Message::OpenRecipients),
+                ]
+                .spacing(5)
+                .align_y(iced::Alignment::Center)
+            }))
+            .push(kdf_warning)
+            .push(text({
                 let cursor = self.content.cursor();
                 let (line, column) = (cursor.position.line, cursor.position.column);
 
                 format!("{}:{}", line + 1, column + 1)
+            }))
+            .spacing(10);
+
+        let lock_banner = self.lock_owner.as_ref().map(|owner| {
+            let since = owner
+                .since
+                .map(|time| format!(" since {}", format_time(time)))
+                .unwrap_or_default();
+            container(
+                row![
+                    text(format!("Read-only: the vault is opened by {owner}{since}"))
+                        .width(Length::Fill),
+                    button(text(if owner.is_stale() {
+                        "Break stale lock"
+                    } else {
+                        "Break lock"
+                    }))
+                    .style(button::danger)
+                    .on_press(Message::BreakLock),
+                ]
+                .spacing(10)
+                .align_y(iced::Alignment::Center),
+            )
+            .padding(5)
+            .style(container::rounded_box)
+        });
+
+        let field_actions = entry_actions(document, self.content.cursor().position.line);
+
+        let editor = text_editor(&self.content)
+            .id("editor")
+            .highlight_with::<search::Highlighter>(
+                (!self.search.pattern.is_empty()).then(|| self.search.clone()),
+                |(), theme: &iced::Theme| highlighter::Format {
+                    color: Some(theme.palette().primary),
+                    font: Some(Font {
+                        weight: font::Weight::Bold,
+                        ..Font::DEFAULT
+                    }),
+                },
+            )
+            .key_binding(|key_press| {
+                let shift = key_press.modifiers.shift();
+                // copy through our clipboard to be able to clear it again
+                match text_editor::Binding::from_key_press(key_press)? {
+                    text_editor::Binding::Copy if shift => {
+                        Some(text_editor::Binding::Custom(Message::CopyValue))
+                    }
+                    text_editor::Binding::Copy => Some(text_editor::Binding::Custom(Message::Copy)),
+                    text_editor::Binding::Cut => Some(text_editor::Binding::Custom(Message::Cut)),
+                    binding => Some(binding),
+                }
             })
-        ]
-        .spacing(10);
-
-        let content = column![
-            header,
-            text_editor(&self.content)
-                .height(Length::Fill)
-                .on_action(Message::ActionPerformed),
-            status,
-        ]
-        .spacing(10)
-        .padding(10);
+            .height(Length::Fill)
+            .on_action(Message::ActionPerformed);
+        let editor = match &self.masked {
+            Some(masked) => masked_view(&self.content, document, masked),
+            None => editor.into(),
+        };
+        let content = column![header]
+            .push(lock_banner)
+            .push(field_actions)
+            .push(
+                row![]
+                    .push(
+                        self.entry_filter
+                            .as_deref()
+                            .map(|filter| entry_sidebar(document, filter)),
+                    )
+                    .push(editor)
+                    .spacing(10)
+                    .height(Length::Fill),
+            )
+            .push(status)
+            .spacing(10)
+            .padding(10);
 
         match self.modal {
             ModalState::None => content.into(),
@@ -369,6 +1645,7 @@ impl Editor {
                                 .padding(5),
                         ]
                         .spacing(5),
+                        keyfile_picker(self.keyfile.as_ref()),
                         button(text("OK")).on_press(Message::LoadPdpwFile),
                     ]
                     .spacing(20),
@@ -400,6 +1677,7 @@ impl Editor {
                                 .padding(5),
                         ]
                         .spacing(5),
+                        keyfile_picker(self.new_keyfile.as_ref()),
                         button(text("OK")).on_press(Message::SetNewPassword),
                     ]
                     .spacing(20),
@@ -409,23 +1687,313 @@ impl Editor {
                 .style(container::rounded_box);
                 crate::modal::modal(content, popup, Message::HideModal)
             }
+            ModalState::Generate => {
+                let popup = container(
+                    column![
+                        text("Generate password").size(24),
+                        self.generator_options(),
+                        text(
+                            self.generated
+                                .as_ref()
+                                .map(|generated| generated.secret.clone())
+                                .unwrap_or_default()
+                        )
+                        .font(Font::MONOSPACE),
+                        text(
+                            self.generated
+                                .as_ref()
+                                .map(|generated| {
+                                    format!("about {:.0} bits of entropy", generated.entropy)
+                                })
+                                .unwrap_or_default()
+                        )
+                        .size(12),
+                        row![
+                            button(text("Regenerate")).on_press(Message::Regenerate),
+                            button(text("Insert")).on_press_maybe(
+                                self.generated.as_ref().map(|_| Message::InsertGenerated)
+                            ),
+                            Space::new().width(Length::Fill),
+                            button(text("Cancel")).on_press(Message::HideModal),
+                        ]
+                        .spacing(10),
+                    ]
+                    .spacing(20),
+                )
+                .width(400)
+                .padding(10)
+                .style(container::rounded_box);
+                crate::modal::modal(content, popup, Message::HideModal)
+            }
             ModalState::Search => {
                 let popup = container(
                     column![
                         text("Search pattern:").size(24),
-                        text_input("", &self.search_string,)
+                        text_input("", &self.search.pattern)
                             .id("search-input")
                             .on_input(Message::SearchString)
                             .on_submit(Message::Search)
                             .padding(5),
-                        checkbox(self.case_sensitive)
-                            .label("case sensitive")
-                            .on_toggle(Message::ToggleCaseSensitive),
-                        button(text("Search")).on_press(Message::Search),
+                        column![
+                            checkbox(self.search.case_sensitive)
+                                .label("case sensitive")
+                                .on_toggle(Message::ToggleCaseSensitive),
+                            checkbox(self.search.whole_word)
+                                .label("whole word")
+                                .on_toggle(Message::ToggleWholeWord),
+                            checkbox(self.search.regex)
+                                .label("regular expression")
+                                .on_toggle(Message::ToggleRegex),
+                        ]
+                        .spacing(5),
+                        text(self.search_counter().unwrap_or_default()).size(12),
+                        text(if self.search.regex {
+                            "The replacement refers to groups with $1 or ${name}."
+                        } else {
+                            ""
+                        })
+                        .size(12),
+                        text_input("Replace with", &self.replacement)
+                            .on_input(Message::ReplaceString)
+                            .on_submit(Message::Replace)
+                            .padding(5),
+                        row![
+                            button(text("Search")).on_press(Message::Search),
+                            button(text("Replace")).on_press(Message::Replace),
+                            button(text({
+                                let count =
+                                    self.search_matches().as_ref().as_ref().map_or(0, Vec::len);
+                                format!("Replace all ({count})")
+                            }))
+                            .on_press(Message::ReplaceAll),
+                        ]
+                        .spacing(10),
                     ]
                     .spacing(20),
                 )
-                .width(300)
+                .width(400)
+                .padding(10)
+                .style(container::rounded_box);
+                crate::modal::modal(content, popup, Message::HideModal)
+            }
+            ModalState::Recipients => {
+                let recipients = self.recipients.iter().enumerate().fold(
+                    column![].spacing(5),
+                    |list, (index, recipient)| {
+                        let label = if recipient.label.is_empty() {
+                            "(no label)"
+                        } else {
+                            recipient.label.as_str()
+                        };
+                        list.push(
+                            row![
+                                column![
+                                    text(if self.is_own_key(recipient) {
+                                        format!("{label} (you)")
+                                    } else {
+                                        label.to_string()
+                                    }),
+                                    text(recipient.fingerprint()).size(12),
+                                ]
+                                .width(Length::Fill),
+                                button(text("Remove"))
+                                    .style(button::danger)
+                                    .on_press(Message::RemoveRecipient(index)),
+                            ]
+                            .spacing(10)
+                            .align_y(iced::Alignment::Center),
+                        )
+                    },
+                );
+                let warning = self.pending_removal.map(|_| {
+                    text(if self.pin.is_empty() {
+                        "This is your own key, you will lose access to the vault! \
+                         Press remove again to confirm."
+                    } else {
+                        "This is your own key, only your master password will open the \
+                         vault afterwards! Press remove again to confirm."
+                    })
+                    .style(text::danger)
+                });
+                let changes = (!self.recipient_changes.is_empty()).then(|| {
+                    column![
+                        text(
+                            "The recipients file changed since your last save, someone \
+                             with access to its directory might have added their key:"
+                        )
+                        .style(text::danger),
+                        text(self.recipient_changes.to_string()).font(Font::MONOSPACE),
+                        button(text("Confirm changes"))
+                            .style(button::danger)
+                            .on_press(Message::ConfirmRecipients),
+                    ]
+                    .spacing(5)
+                });
+                let popup = container(
+                    column![
+                        text("Recipients").size(24),
+                        if self.recipients.is_empty() {
+                            Element::from(text("Encrypted with the master password only"))
+                        } else {
+                            scrollable(recipients).height(Length::Shrink).into()
+                        },
+                    ]
+                    .push(changes)
+                    .push(warning)
+                    .push(
+                        column![
+                            text_input("age1... or ssh-ed25519 ...", &self.recipient_key)
+                                .id("recipient-key-input")
+                                .on_input(Message::RecipientKeyInput)
+                                .on_submit(Message::AddRecipient)
+                                .padding(5),
+                            text_input("Label", &self.recipient_label)
+                                .on_input(Message::RecipientLabelInput)
+                                .on_submit(Message::AddRecipient)
+                                .padding(5),
+                            button(text("Add")).on_press(Message::AddRecipient),
+                        ]
+                        .spacing(5),
+                    )
+                    .push(text("Changes take effect with the next save").size(12))
+                    .spacing(20),
+                )
+                .width(600)
+                .padding(10)
+                .style(container::rounded_box);
+                crate::modal::modal(content, popup, Message::HideModal)
+            }
+            ModalState::Merge => {
+                let hunks = self
+                    .merge
+                    .iter()
+                    .filter_map(|hunk| match hunk {
+                        Hunk::Conflict { mine, theirs } => Some((mine.concat(), theirs.concat())),
+                        Hunk::Resolved(_) => None,
+                    })
+                    .enumerate()
+                    .fold(column![].spacing(15), |list, (index, (mine, theirs))| {
+                        let chosen = self.merge_choices.get(index).copied().flatten();
+                        let choice = |label, choice| {
+                            button(text(label))
+                                .style(if chosen == Some(choice) {
+                                    button::primary
+                                } else {
+                                    button::secondary
+                                })
+                                .on_press(Message::MergeChoice(index, choice))
+                        };
+                        list.push(
+                            column![
+                                text(format!("Conflict {}", index + 1)),
+                                row![
+                                    column![text("Mine").style(text::primary), text(mine)]
+                                        .width(Length::FillPortion(1)),
+                                    column![text("Theirs").style(text::primary), text(theirs)]
+                                        .width(Length::FillPortion(1)),
+                                ]
+                                .spacing(10),
+                                row![
+                                    choice("Mine", Choice::Mine),
+                                    choice("Theirs", Choice::Theirs),
+                                    choice("Both", Choice::Both),
+                                ]
+                                .spacing(5),
+                            ]
+                            .spacing(5),
+                        )
+                    });
+                let popup = container(
+                    column![
+                        text("The vault changed on disk").size(24),
+                        text("Pick which version of the conflicting lines to keep."),
+                        scrollable(hunks).height(300),
+                        button(text("Merge and save")).on_press(Message::ApplyMerge),
+                    ]
+                    .spacing(20),
+                )
+                .width(600)
+                .padding(10)
+                .style(container::rounded_box);
+                crate::modal::modal(content, popup, Message::HideModal)
+            }
+            ModalState::Quit(_) => {
+                let locked = self.is_locked();
+                let popup = container(
+                    column![
+                        text("Unsaved changes").size(24),
+                        text(if locked {
+                            "The vault is locked, unlock it to save your changes."
+                        } else if self.is_saving {
+                            "Saving, pdpw quits once your changes are written."
+                        } else {
+                            "Save your changes before quitting?"
+                        }),
+                        row![
+                            button(text("Save and quit")).on_press_maybe(
+                                (!locked && !self.is_loading).then_some(Message::SaveAndQuit)
+                            ),
+                            button(text("Discard"))
+                                .style(button::danger)
+                                .on_press(Message::Quit),
+                            button(text("Cancel"))
+                                .style(button::secondary)
+                                .on_press(Message::CancelQuit),
+                        ]
+                        .spacing(10),
+                    ]
+                    .spacing(20),
+                )
+                .width(400)
+                .padding(10)
+                .style(container::rounded_box);
+                crate::modal::modal(content, popup, Message::CancelQuit)
+            }
+            ModalState::Restore => {
+                let backups = self.backups.iter().enumerate().fold(
+                    column![].spacing(5),
+                    |list, (index, backup)| {
+                        let selected = self
+                            .backup_preview
+                            .as_ref()
+                            .is_some_and(|(i, _)| *i == index);
+                        list.push(
+                            button(text(format!(
+                                "#{}  {}  {} bytes",
+                                index + 1,
+                                backup.modified.map_or_else(|| "?".into(), format_time),
+                                backup.size
+                            )))
+                            .width(Length::Fill)
+                            .style(if selected {
+                                button::primary
+                            } else {
+                                button::secondary
+                            })
+                            .on_press(Message::PreviewBackup(index)),
+                        )
+                    },
+                );
+                let preview = self
+                    .backup_preview
+                    .as_ref()
+                    .map_or_else(String::new, |(_, contents)| contents.to_string());
+                let popup = container(
+                    column![
+                        text("Restore backup").size(24),
+                        if self.backups.is_empty() {
+                            Element::from(text("No backups found"))
+                        } else {
+                            backups.into()
+                        },
+                        scrollable(text(preview)).height(200),
+                        button(text("Restore")).on_press_maybe(
+                            self.backup_preview.as_ref().map(|_| Message::RestoreBackup)
+                        ),
+                    ]
+                    .spacing(20),
+                )
+                .width(500)
                 .padding(10)
                 .style(container::rounded_box);
                 crate::modal::modal(content, popup, Message::HideModal)
@@ -434,28 +2002,216 @@ impl Editor {
     }
 }
 
+/// Entries matching `filter`, best matches first.
+fn entry_sidebar<'a>(document: &Document, filter: &'a str) -> Element<'a, Message> {
+    let mut entries: Vec<_> = document
+        .entries()
+        .filter_map(|entry| Some((entry.score(filter)?, entry)))
+        .collect();
+    if !filter.is_empty() {
+        entries.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+    }
+    let list = entries
+        .into_iter()
+        .fold(column![].spacing(2), |list, (_, entry)| {
+            let username = entry
+                .field(FieldKind::Username)
+                .map(|field| text(field.value.clone()).size(12));
+            list.push(
+                button(column![text(entry.title().to_string())].push(username))
+                    .width(Length::Fill)
+                    .style(button::text)
+                    .on_press(Message::JumpToEntry(entry.line)),
+            )
+        });
+    column![
+        text_input("Filter entries", filter)
+            .id("entry-filter")
+            .on_input(Message::EntryFilter)
+            .padding(5),
+        scrollable(list).height(Length::Fill),
+    ]
+    .spacing(10)
+    .width(250)
+    .into()
+}
+
+/// Copy buttons and the TOTP code for the entry the cursor is in.
+fn entry_actions<'a>(document: &Document, line: usize) -> Option<Element<'a, Message>> {
+    let entry = document.entry_at(line)?;
+    let copy = |label: &'static str, message, enabled: bool| {
+        button(text(label))
+            .padding([2, 8])
+            .on_press_maybe(enabled.then_some(message))
+    };
+    let totp = entry.totp().map(|secret| match Totp::parse(secret) {
+        Ok(totp) => {
+            let (code, left) = totp.now();
+            Element::from(
+                row![
+                    text(countdown_ring(left, totp.period())),
+                    text(code).font(Font::MONOSPACE),
+                    text(format!("{}s", left.as_secs())).size(12),
+                    copy("Copy code", Message::CopyTotp, true),
+                ]
+                .spacing(5)
+                .align_y(iced::Alignment::Center),
+            )
+        }
+        Err(e) => text(format!("{e:#}")).style(text::danger).into(),
+    });
+    Some(
+        row![text(entry.title().to_string()).width(Length::Fill)]
+            .push(totp)
+            .push(copy(
+                "Copy value",
+                Message::CopyValue,
+                entry.field_at(line).is_some(),
+            ))
+            .push(copy(
+                "Copy username",
+                Message::CopyField(FieldKind::Username),
+                entry.field(FieldKind::Username).is_some(),
+            ))
+            .push(copy(
+                "Copy URL",
+                Message::CopyField(FieldKind::Url),
+                entry.field(FieldKind::Url).is_some(),
+            ))
+            .spacing(5)
+            .align_y(iced::Alignment::Center)
+            .into(),
+    )
+}
+
+/// Start and end of the selection, or the cursor position twice.
+fn selection_bounds(cursor: text_editor::Cursor) -> ((usize, usize), (usize, usize)) {
+    let position = (cursor.position.line, cursor.position.column);
+    let anchor = cursor
+        .selection
+        .map_or(position, |selection| (selection.line, selection.column));
+    (position.min(anchor), position.max(anchor))
+}
+
+/// A pie that empties as the TOTP code runs out.
+fn countdown_ring(left: Duration, period: Duration) -> &'static str {
+    const RINGS: [&str; 5] = ["○", "◔", "◑", "◕", "●"];
+    let quarters = (left.as_millis() * 4 + period.as_millis() / 2) / period.as_millis().max(1);
+    RINGS[usize::try_from(quarters).unwrap_or(4).min(4)]
+}
+
+/// The vault as read-only text with password values replaced by dots, unless
+/// the line is hovered or revealed. Clicking a line moves the cursor there.
+fn masked_view<'a>(
+    content: &text_editor::Content,
+    document: &Document,
+    masked: &Masked,
+) -> Element<'a, Message> {
+    let secrets: HashMap<usize, String> = document.secrets().collect();
+    let cursor = content.cursor().position.line;
+    let lines = content
+        .lines()
+        .enumerate()
+        .fold(column![], |lines, (number, line)| {
+            let shown = match secrets.get(&number) {
+                Some(masked_line)
+                    if masked.hovered != Some(number) && !masked.revealed.contains(&number) =>
+                {
+                    masked_line.clone()
+                }
+                _ => line.text.to_string(),
+            };
+            let line = container(text(shown))
+                .width(Length::Fill)
+                .style(if number == cursor {
+                    container::rounded_box
+                } else {
+                    container::transparent
+                });
+            lines.push(
+                mouse_area(line)
+                    .on_enter(Message::HoverLine(Some(number)))
+                    .on_exit(Message::HoverLine(None))
+                    .on_press(Message::MoveToLine(number))
+                    .on_double_click(Message::RevealLine(number)),
+            )
+        });
+    container(scrollable(lines).height(Length::Fill))
+        .padding(5)
+        .style(container::bordered_box)
+        .into()
+}
+
+/// Shows the selected keyfile with buttons to pick another one or drop it.
+fn keyfile_picker(keyfile: Option<&PathBuf>) -> Element<'_, Message> {
+    let name = keyfile
+        .and_then(|path| path.file_name())
+        .map_or_else(|| "No keyfile".into(), |name| name.to_string_lossy());
+    row![
+        text(name).width(Length::Fill),
+        button(text("Keyfile")).on_press(Message::PickKeyfile),
+    ]
+    .push(keyfile.map(|_| button(text("Clear")).on_press(Message::ClearKeyfile)))
+    .spacing(5)
+    .align_y(iced::Alignment::Center)
+    .into()
+}
+
+/// Formats `time` as `YYYY-MM-DD HH:MM UTC`.
+fn format_time(time: std::time::SystemTime) -> String {
+    let secs = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let days = i64::try_from(secs / 86_400).unwrap_or_default();
+    let (hour, minute) = (secs % 86_400 / 3600, secs % 3600 / 60);
+    // civil date from days since epoch (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02} {hour:02}:{minute:02} UTC")
+}
+
 #[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     LoadError(String),
     SaveError(String),
+    /// The vault on disk is no longer the one we loaded
+    Changed(Snapshot),
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::LoadError(s) | Self::SaveError(s) => write!(f, "LoadError: {s}"),
+            Self::LoadError(s) => write!(f, "LoadError: {s}"),
+            Self::SaveError(s) => write!(f, "SaveError: {s}"),
+            Self::Changed(_) => write!(f, "SaveError: The vault changed on disk"),
         }
     }
 }
 
-async fn load_content(path: PathBuf, pin: String) -> Result<Arc<String>, Error> {
-    let contents = load_pdpw_file(path.as_path(), &pin).await.map_err(|e| {
+async fn load_content(path: PathBuf, keys: Keys) -> Result<Snapshot, Error> {
+    let load_error = |e: anyhow::Error| {
         Error::LoadError(format!(
             "Couldn't load *pdpw file from {}: [{e}]",
             path.display()
         ))
-    })?;
-    Ok(Arc::new(contents))
+    };
+    // taken before reading, a change in between shows up as conflict on save
+    let digest = vault_digest(&path).await.map_err(load_error)?;
+    let contents = load_pdpw_file(path.as_path(), &keys)
+        .await
+        .map_err(load_error)?;
+    Ok(Snapshot {
+        contents: Arc::new(contents),
+        digest,
+    })
 }
 
 #[allow(clippy::unused_async)]
@@ -463,9 +2219,209 @@ async fn set_pdpw_path(path: PathBuf) -> PathBuf {
     path
 }
 
-async fn save_file(path: PathBuf, pin: String, contents: String) -> Result<PathBuf, Error> {
-    store_pdpw_file(&path, pin.as_str(), contents.as_str())
+async fn check_work_factor(path: PathBuf) -> Option<u8> {
+    vault_work_factor(&path).await
+}
+
+async fn load_vault_recipients(path: PathBuf) -> Result<(Vec<Recipient>, RecipientChanges), Error> {
+    let loaded = async {
+        let recipients = load_recipients(&path).await?;
+        let changes = recipient_changes(&path, &recipients).await?;
+        anyhow::Ok((recipients, changes))
+    };
+    loaded.await.map_err(|e| Error::LoadError(format!("{e:#}")))
+}
+
+async fn list_vault_backups(path: PathBuf) -> Result<Vec<Backup>, Error> {
+    list_backups(&path)
+        .await
+        .map_err(|e| Error::LoadError(format!("{e:#}")))
+}
+
+async fn load_backup_content(
+    pdpw_file: PathBuf,
+    path: PathBuf,
+    keys: Keys,
+) -> Result<Arc<String>, Error> {
+    let contents = load_backup(&pdpw_file, &path, &keys)
+        .await
+        .map_err(|e| Error::LoadError(format!("{e:#}")))?;
+    Ok(Arc::new(contents))
+}
+
+async fn save_file(
+    path: PathBuf,
+    keys: Keys,
+    contents: String,
+    backups: usize,
+    armor: Option<bool>,
+    recipients: Vec<Recipient>,
+    expected: Option<[u8; 32]>,
+) -> Result<Snapshot, Error> {
+    let save_error = |e: anyhow::Error| Error::SaveError(format!("{e:#}"));
+    let digest = vault_digest(&path).await.map_err(save_error)?;
+    // a deleted vault has no lines to merge, each one would count as removed
+    // on their side, so it is written again as is
+    if digest.is_some() && digest != expected {
+        let theirs = load_pdpw_file(&path, &keys).await.map_err(save_error)?;
+        return Err(Error::Changed(Snapshot {
+            contents: Arc::new(theirs),
+            digest,
+        }));
+    }
+    store_pdpw_file(&path, &keys, contents.as_str(), backups, armor)
+        .await
+        .map_err(save_error)?;
+    // only record the new recipients once the vault is encrypted to them
+    store_recipients(&path, &recipients)
+        .await
+        .map_err(save_error)?;
+    remember_recipients(&path, &recipients)
+        .await
+        .map_err(save_error)?;
+    Ok(Snapshot {
+        digest: vault_digest(&path).await.map_err(save_error)?,
+        contents: Arc::new(contents),
+    })
+}
+
+async fn read_wordlist(path: PathBuf) -> Result<Arc<Vec<String>>, Error> {
+    load_wordlist(&path)
+        .await
+        .map_err(|e| Error::LoadError(format!("{}: {e:#}", path.display())))
+}
+
+async fn pick_keyfile() -> Option<PathBuf> {
+    rfd::AsyncFileDialog::new()
+        .set_title("Select keyfile")
+        .pick_file()
+        .await
+        .map(|file| file.path().to_path_buf())
+}
+
+async fn lock_file(path: PathBuf, force: bool) -> Result<Result<Arc<VaultLock>, LockOwner>, Error> {
+    lock_vault(&path, force)
+        .await
+        .map(|lock| lock.map(Arc::new))
+        .map_err(|e| Error::LoadError(format!("{e:#}")))
+}
+
+async fn check_disk(path: PathBuf) -> Option<[u8; 32]> {
+    vault_digest(&path).await.ok().flatten()
+}
+
+async fn seal_content(
+    path: PathBuf,
+    keys: Keys,
+    contents: String,
+    base: Snapshot,
+) -> Result<Arc<Sealed>, Error> {
+    let sealed = async {
+        anyhow::Ok(Sealed {
+            contents: seal(&path, &keys, &contents).await?,
+            base: seal(&path, &keys, &base.contents).await?,
+            digest: base.digest,
+        })
+    };
+    sealed
+        .await
+        .map(Arc::new)
+        .map_err(|e| Error::SaveError(format!("{e:#}")))
+}
+
+/// The unsaved changes and the snapshot they are based on.
+async fn unseal_content(
+    path: PathBuf,
+    sealed: Arc<Sealed>,
+    keys: Keys,
+) -> Result<(Arc<String>, Snapshot), Error> {
+    let unsealed = async {
+        let contents = unseal(&path, &sealed.contents, &keys).await?;
+        let base = unseal(&path, &sealed.base, &keys).await?;
+        anyhow::Ok((
+            Arc::new(contents),
+            Snapshot {
+                contents: Arc::new(base),
+                digest: sealed.digest,
+            },
+        ))
+    };
+    unsealed
         .await
-        .map_err(|e| Error::SaveError(format!("{e}")))?;
-    Ok(path)
+        .map_err(|e| Error::LoadError(format!("{e:#}")))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::kdf::KdfPolicy;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn saves_a_vault_deleted_on_disk_unmerged() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vault.pdpw");
+        let keys = Keys {
+            kdf: KdfPolicy::LogN(10),
+            ..Config::default().keys("secret")
+        };
+        let save = |contents: &str, expected| {
+            block_on(save_file(
+                path.clone(),
+                keys.clone(),
+                contents.into(),
+                0,
+                None,
+                vec![],
+                expected,
+            ))
+        };
+        let saved = save("# Mail\nuser: me\n", None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mine = "# Mail\nuser: me\npassword: hunter2\n";
+        let saved = save(mine, saved.digest).unwrap();
+        assert_eq!(*saved.contents, mine);
+        let loaded = block_on(load_content(path.clone(), keys.clone())).unwrap();
+        assert_eq!(*loaded.contents, mine);
+
+        // changed rather than deleted still merges
+        assert!(matches!(save("other", None), Err(Error::Changed(_))));
+    }
+
+    #[test]
+    fn searches_again_after_edits() {
+        let path = Arc::new("vault.pdpw".to_string());
+        let (mut editor, _) = Editor::new(&path, Config::default(), None);
+        editor.modal = ModalState::None;
+        editor.set_content("user: me\nuser: you");
+        editor.search.pattern = "user".into();
+        let count = |editor: &Editor| {
+            editor
+                .search_matches()
+                .as_ref()
+                .as_ref()
+                .map_or(0, Vec::len)
+        };
+        assert_eq!(count(&editor), 2);
+        assert!(Rc::ptr_eq(
+            &editor.search_matches(),
+            &editor.search_matches()
+        ));
+
+        let _ = editor.update(Message::ActionPerformed(text_editor::Action::Edit(
+            text_editor::Edit::Paste(Arc::new("user: them\n".into())),
+        )));
+        assert_eq!(count(&editor), 3);
+        editor.search.whole_word = true;
+        editor.search.pattern = "use".into();
+        assert_eq!(count(&editor), 0);
+    }
 }
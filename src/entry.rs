@@ -0,0 +1,448 @@
+use std::fmt::Display;
+
+use crate::totp::is_otpauth;
+
+/// Longest key still taken for a field, longer ones are most likely prose.
+const MAX_KEY_LEN: usize = 32;
+
+/// What a field holds, going by its key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum FieldKind {
+    Username,
+    Password,
+    Url,
+    /// TOTP secret, base32 or an `otpauth://` URI
+    Totp,
+    Other,
+}
+
+/// A `key: value` line of an entry.
+///
+/// Everything around key and value is kept, so writing the field gives back
+/// the original line.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Field {
+    indent: String,
+    pub(crate) key: String,
+    /// The colon with the whitespace around it
+    separator: String,
+    pub(crate) value: String,
+    /// Trailing whitespace, including the `\r` of CRLF files
+    trailing: String,
+}
+
+impl Field {
+    pub(crate) fn kind(&self) -> FieldKind {
+        match self.key.to_lowercase().as_str() {
+            "user" | "username" | "login" | "email" | "e-mail" | "mail" | "account" => {
+                FieldKind::Username
+            }
+            "password" | "pass" | "passwd" | "pw" | "pin" | "passphrase" | "secret" => {
+                FieldKind::Password
+            }
+            "url" | "uri" | "website" | "site" | "link" | "web" => FieldKind::Url,
+            "totp" | "otp" | "2fa" | "mfa" => FieldKind::Totp,
+            _ => FieldKind::Other,
+        }
+    }
+
+    /// The line with the value hidden.
+    pub(crate) fn masked(&self) -> String {
+        format!("{}{}{}••••••", self.indent, self.key, self.separator)
+    }
+
+    fn parse(line: &str) -> Option<Self> {
+        let content = line.trim_start();
+        let indent = &line[..line.len() - content.len()];
+        let (key, rest) = content.split_once(':')?;
+        let valid_key = |key: &str| {
+            !key.is_empty()
+                && key.len() <= MAX_KEY_LEN
+                && key.starts_with(|c: char| c.is_alphabetic())
+                && key
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_' | '.'))
+        };
+        let key_end = key.trim_end();
+        // `https://...` is a URL, not a field
+        if !valid_key(key_end) || rest.starts_with("//") {
+            return None;
+        }
+        let value = rest.trim();
+        let value_start = rest.len() - rest.trim_start().len();
+        let separator = format!("{}:{}", &key[key_end.len()..], &rest[..value_start]);
+        let trailing = &rest[value_start + value.len()..];
+        Some(Self {
+            indent: indent.to_string(),
+            key: key_end.to_string(),
+            separator,
+            value: value.to_string(),
+            trailing: trailing.to_string(),
+        })
+    }
+}
+
+impl Display for Field {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}{}{}{}",
+            self.indent, self.key, self.separator, self.value, self.trailing
+        )
+    }
+}
+
+/// A line below the title of an entry.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Line {
+    Field(Field),
+    /// Anything else, e.g. continued notes
+    Text(String),
+}
+
+impl Display for Line {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Field(field) => field.fmt(f),
+            Self::Text(text) => f.write_str(text),
+        }
+    }
+}
+
+/// A title line followed by `key: value` lines, e.g.
+///
+/// ```text
+/// # Github
+/// user: octocat
+/// password: hunter2
+/// url: https://github.com
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Entry {
+    title: String,
+    pub(crate) lines: Vec<Line>,
+    /// Index of the title line in the vault
+    pub(crate) line: usize,
+}
+
+impl Entry {
+    /// The title without heading markers.
+    pub(crate) fn title(&self) -> &str {
+        self.title.trim().trim_start_matches('#').trim()
+    }
+
+    pub(crate) fn fields(&self) -> impl Iterator<Item = &Field> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Field(field) => Some(field),
+            Line::Text(_) => None,
+        })
+    }
+
+    /// The first field of the given kind.
+    pub(crate) fn field(&self, kind: FieldKind) -> Option<&Field> {
+        self.fields().find(|field| field.kind() == kind)
+    }
+
+    /// The field on line `line` of the vault, if it is one of this entry.
+    pub(crate) fn field_at(&self, line: usize) -> Option<&Field> {
+        match self.lines.get(line.checked_sub(self.line + 1)?)? {
+            Line::Field(field) => Some(field),
+            Line::Text(_) => None,
+        }
+    }
+
+    /// The TOTP secret of the entry: a `totp:` field, or an `otpauth://totp/`
+    /// URI on its own line or as value of any field.
+    pub(crate) fn totp(&self) -> Option<&str> {
+        self.field(FieldKind::Totp)
+            .map(|field| field.value.as_str())
+            .or_else(|| {
+                self.lines.iter().find_map(|line| match line {
+                    Line::Field(field) if is_otpauth(&field.value) => Some(field.value.as_str()),
+                    Line::Text(text) if is_otpauth(text) => Some(text.trim()),
+                    _ => None,
+                })
+            })
+    }
+
+    /// Whether line `line` of the vault belongs to this entry.
+    pub(crate) fn contains(&self, line: usize) -> bool {
+        (self.line..=self.line + self.lines.len()).contains(&line)
+    }
+
+    /// How well `filter` fuzzy matches the title, usernames or URLs, `None`
+    /// if it doesn't match at all.
+    pub(crate) fn score(&self, filter: &str) -> Option<usize> {
+        let searchable = self
+            .fields()
+            .filter(|field| matches!(field.kind(), FieldKind::Username | FieldKind::Url))
+            .map(|field| field.value.as_str());
+        std::iter::once(self.title())
+            .chain(searchable)
+            .filter_map(|text| fuzzy_score(filter, text))
+            .max()
+    }
+}
+
+/// Scores `pattern` as case insensitive subsequence of `text`: consecutive
+/// characters and matches at the start of words count more.
+fn fuzzy_score(pattern: &str, text: &str) -> Option<usize> {
+    let mut score = 0;
+    let mut streak = 0;
+    let mut previous = None;
+    let mut text = text.chars().flat_map(char::to_lowercase);
+    for wanted in pattern.chars().flat_map(char::to_lowercase) {
+        loop {
+            let c = text.next()?;
+            let word_start = previous.is_none_or(|p: char| !p.is_alphanumeric());
+            previous = Some(c);
+            if c == wanted {
+                streak += 1;
+                score += streak + if word_start { 2 } else { 0 };
+                break;
+            }
+            streak = 0;
+        }
+    }
+    Some(score)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Block {
+    Entry(Entry),
+    /// Free text and blank lines, kept as they are
+    Text(Vec<String>),
+}
+
+/// The vault text split into entries and free text.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Document {
+    pub(crate) blocks: Vec<Block>,
+}
+
+impl Document {
+    /// Entries are runs of non blank lines that start with a title and have
+    /// at least one field. Blank lines and headings (`# ...`) start a new
+    /// block, anything that isn't an entry stays free text.
+    pub(crate) fn parse(text: &str) -> Self {
+        let mut document = Self::default();
+        let mut block: Vec<&str> = vec![];
+        let mut block_start = 0;
+        for (number, line) in text.split('\n').enumerate() {
+            let blank = line.trim().is_empty();
+            if blank || line.trim_start().starts_with('#') {
+                document.push_block(block_start, std::mem::take(&mut block));
+                block_start = number + usize::from(blank);
+            }
+            if blank {
+                document.push_text(line);
+            } else {
+                block.push(line);
+            }
+        }
+        document.push_block(block_start, block);
+        document
+    }
+
+    fn push_text(&mut self, line: &str) {
+        match self.blocks.last_mut() {
+            Some(Block::Text(lines)) => lines.push(line.to_string()),
+            _ => self.blocks.push(Block::Text(vec![line.to_string()])),
+        }
+    }
+
+    fn push_block(&mut self, line: usize, block: Vec<&str>) {
+        let Some((title, rest)) = block.split_first() else {
+            return;
+        };
+        let lines: Vec<Line> = rest
+            .iter()
+            .map(|line| Field::parse(line).map_or_else(|| Line::Text((*line).into()), Line::Field))
+            .collect();
+        if lines.iter().any(|line| matches!(line, Line::Field(_))) {
+            self.blocks.push(Block::Entry(Entry {
+                title: (*title).to_string(),
+                lines,
+                line,
+            }));
+        } else {
+            for line in block {
+                self.push_text(line);
+            }
+        }
+    }
+
+    pub(crate) fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.blocks.iter().filter_map(|block| match block {
+            Block::Entry(entry) => Some(entry),
+            Block::Text(_) => None,
+        })
+    }
+
+    /// The entry line `line` of the vault belongs to.
+    pub(crate) fn entry_at(&self, line: usize) -> Option<&Entry> {
+        self.entries().find(|entry| entry.contains(line))
+    }
+
+    /// Line numbers of passwords and TOTP secrets, with the line as shown
+    /// when masked. Covers every line, not only those of entries, so a lone
+    /// `password: ...` line is hidden as well.
+    pub(crate) fn secrets(&self) -> impl Iterator<Item = (usize, String)> {
+        self.to_string()
+            .split('\n')
+            .enumerate()
+            .filter_map(|(number, line)| Some((number, masked_secret(line)?)))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+}
+
+/// `line` as shown when masked, `None` if it holds no secret.
+fn masked_secret(line: &str) -> Option<String> {
+    match Field::parse(line) {
+        Some(field)
+            if matches!(field.kind(), FieldKind::Password | FieldKind::Totp)
+                || is_otpauth(&field.value) =>
+        {
+            Some(field.masked())
+        }
+        _ if is_otpauth(line) => Some("otpauth://••••••".to_string()),
+        _ => None,
+    }
+}
+
+/// Writes back the exact text the document was parsed from.
+impl Display for Document {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut first = true;
+        let mut line = |f: &mut std::fmt::Formatter<'_>, line: &dyn Display| {
+            if !std::mem::take(&mut first) {
+                f.write_str("\n")?;
+            }
+            line.fmt(f)
+        };
+        for block in &self.blocks {
+            match block {
+                Block::Entry(entry) => {
+                    line(f, &entry.title)?;
+                    for entry_line in &entry.lines {
+                        line(f, entry_line)?;
+                    }
+                }
+                Block::Text(lines) => {
+                    for text in lines {
+                        line(f, text)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn titles(document: &Document) -> Vec<&str> {
+        document.entries().map(Entry::title).collect()
+    }
+
+    fn round_trip(text: &str) -> Document {
+        let document = Document::parse(text);
+        assert_eq!(document.to_string(), text);
+        document
+    }
+
+    #[test]
+    fn entries_and_free_text() {
+        let document = round_trip(
+            "Notes about this vault\n\n# Github\nuser: octocat\npassword: hunter2\n\nnot an entry\n",
+        );
+        assert_eq!(titles(&document), vec!["Github"]);
+        let github = document
+            .entries()
+            .next()
+            .into_iter()
+            .flat_map(Entry::fields);
+        assert_eq!(
+            github
+                .map(|f| (f.key.as_str(), f.value.as_str()))
+                .collect::<Vec<_>>(),
+            vec![("user", "octocat"), ("password", "hunter2")]
+        );
+        assert_eq!(document.entry_at(3).map(|e| e.line), Some(2));
+        assert!(document.entry_at(6).is_none());
+    }
+
+    #[test]
+    fn crlf() {
+        let document = round_trip("Mail\r\nuser: me@example.com\r\npassword: s3cret \r\n\r\n");
+        let mail = document.entries().next();
+        let password = mail.and_then(|e| e.field(FieldKind::Password));
+        assert_eq!(password.map(|f| f.value.as_str()), Some("s3cret"));
+        assert_eq!(mail.map(Entry::title), Some("Mail"));
+    }
+
+    #[test]
+    fn missing_trailing_newline() {
+        let document = round_trip("Bank\npin: 1234");
+        assert_eq!(titles(&document), vec!["Bank"]);
+        round_trip("");
+        round_trip("\n");
+    }
+
+    #[test]
+    fn headings_start_entries() {
+        // no blank line between the entries
+        let document = round_trip("# One\nuser: a\n## Two\nuser: b\n# Section\nprose only\n");
+        assert_eq!(titles(&document), vec!["One", "Two"]);
+        assert_eq!(
+            document.entries().map(|e| e.line).collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+    }
+
+    #[test]
+    fn runs_of_blank_lines() {
+        let document = round_trip("\n\n\nWifi\npassword: guest\n\n\n\nVPN\nuser: me\n\n");
+        assert_eq!(titles(&document), vec!["Wifi", "VPN"]);
+        assert_eq!(
+            document.entries().map(|e| e.line).collect::<Vec<_>>(),
+            vec![3, 8]
+        );
+    }
+
+    #[test]
+    fn indented_fields() {
+        let document =
+            round_trip("Server\n    user: root\n\tpassword:\tx y \n  https://example.com\n");
+        let server = document.entries().next();
+        let values: Vec<_> = server
+            .into_iter()
+            .flat_map(Entry::fields)
+            .map(|f| (f.kind(), f.value.as_str()))
+            .collect();
+        assert_eq!(
+            values,
+            vec![(FieldKind::Username, "root"), (FieldKind::Password, "x y")]
+        );
+        assert_eq!(
+            document.secrets().collect::<Vec<_>>(),
+            vec![(2, "\tpassword:\t••••••".to_string())]
+        );
+    }
+
+    #[test]
+    fn secrets_outside_entries() {
+        let document = round_trip("password: hunter2\n\npin: 1234\nuser: me\n\nnotes: none\n");
+        assert_eq!(document.entries().count(), 1);
+        assert_eq!(
+            document.secrets().collect::<Vec<_>>(),
+            vec![
+                (0, "password: ••••••".to_string()),
+                (2, "pin: ••••••".to_string())
+            ]
+        );
+    }
+}
@@ -0,0 +1,195 @@
+use anyhow::bail;
+use rand::{Rng, rngs::OsRng, seq::SliceRandom};
+use std::{path::Path, sync::Arc};
+
+const LOWERCASE: &str = "abcdefghijklmnopqrstuvwxyz";
+const UPPERCASE: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DIGITS: &str = "0123456789";
+const SYMBOLS: &str = "!#$%&()*+,-./:;<=>?@[]^_{|}~";
+/// Characters that are easily confused with each other when read or typed
+const AMBIGUOUS: &str = "Il1|O0o`'\"";
+
+/// Built-in words for passphrases: the BIP-39 English wordlist, 2048 common
+/// words of 3 to 8 letters, so 11 bits per word. `wordlist` in the config file
+/// takes a longer one like the EFF large wordlist.
+const WORDS: &str = include_str!("bip39_english.txt");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Mode {
+    Password,
+    Passphrase,
+}
+
+/// What to generate, as set in the generator dialog.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::struct_excessive_bools)] // one per checkbox
+pub(crate) struct Options {
+    pub(crate) mode: Mode,
+    pub(crate) length: u8,
+    pub(crate) lowercase: bool,
+    pub(crate) uppercase: bool,
+    pub(crate) digits: bool,
+    pub(crate) symbols: bool,
+    pub(crate) exclude_ambiguous: bool,
+    /// At least one character of every selected class
+    pub(crate) require_each: bool,
+    pub(crate) words: u8,
+    pub(crate) separator: String,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Password,
+            length: 20,
+            lowercase: true,
+            uppercase: true,
+            digits: true,
+            symbols: true,
+            exclude_ambiguous: false,
+            require_each: true,
+            words: 6,
+            separator: "-".into(),
+        }
+    }
+}
+
+impl Options {
+    fn classes(&self) -> Vec<Vec<char>> {
+        [
+            (self.lowercase, LOWERCASE),
+            (self.uppercase, UPPERCASE),
+            (self.digits, DIGITS),
+            (self.symbols, SYMBOLS),
+        ]
+        .into_iter()
+        .filter(|(selected, _)| *selected)
+        .map(|(_, class)| {
+            class
+                .chars()
+                .filter(|c| !(self.exclude_ambiguous && AMBIGUOUS.contains(*c)))
+                .collect()
+        })
+        .collect()
+    }
+}
+
+/// A generated password with its estimated entropy in bits.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Generated {
+    pub(crate) secret: String,
+    pub(crate) entropy: f64,
+}
+
+/// Generates a password or passphrase, `wordlist` replaces the built-in
+/// words.
+pub(crate) fn generate(
+    options: &Options,
+    wordlist: Option<&[String]>,
+) -> anyhow::Result<Generated> {
+    match options.mode {
+        Mode::Password => generate_password(options),
+        Mode::Passphrase => {
+            let words: Vec<&str> = match wordlist {
+                Some(wordlist) => wordlist.iter().map(String::as_str).collect(),
+                None => WORDS
+                    .lines()
+                    .filter(|line| !line.starts_with('#'))
+                    .collect(),
+            };
+            generate_passphrase(&words, options)
+        }
+    }
+}
+
+fn generate_password(options: &Options) -> anyhow::Result<Generated> {
+    let classes = options.classes();
+    let pool: Vec<char> = classes.iter().flatten().copied().collect();
+    if pool.is_empty() {
+        bail!("Select at least one character class!");
+    }
+    let length = usize::from(options.length);
+    if options.require_each && length < classes.len() {
+        bail!("The password is too short to contain every class!");
+    }
+    // drawing again until every class is present keeps the characters
+    // uniformly distributed, unlike placing one of each class
+    let secret = loop {
+        let secret: Vec<char> = (0..length)
+            .map(|_| pool[OsRng.gen_range(0..pool.len())])
+            .collect();
+        if !options.require_each
+            || classes
+                .iter()
+                .all(|class| secret.iter().any(|c| class.contains(c)))
+        {
+            break secret.into_iter().collect();
+        }
+    };
+    Ok(Generated {
+        secret,
+        entropy: f64::from(options.length) * bits(pool.len()),
+    })
+}
+
+fn generate_passphrase(words: &[&str], options: &Options) -> anyhow::Result<Generated> {
+    if words.len() < 2 {
+        bail!("The wordlist needs at least two words!");
+    }
+    let secret = (0..options.words)
+        .map(|_| *words.choose(&mut OsRng).unwrap_or(&""))
+        .collect::<Vec<_>>()
+        .join(&options.separator);
+    Ok(Generated {
+        secret,
+        entropy: f64::from(options.words) * bits(words.len()),
+    })
+}
+
+/// Entropy of a uniform choice from `choices` options.
+fn bits(choices: usize) -> f64 {
+    f64::from(u32::try_from(choices).unwrap_or(u32::MAX)).log2()
+}
+
+/// Reads a wordlist with one word per line. Diceware lists like the EFF ones
+/// prefix every word with its dice roll, only the last column is taken.
+pub(crate) async fn load_wordlist(path: &Path) -> anyhow::Result<Arc<Vec<String>>> {
+    let data = tokio::fs::read_to_string(path).await?;
+    let mut words: Vec<String> = data
+        .lines()
+        .filter_map(|line| line.split_whitespace().last())
+        .map(ToString::to_string)
+        .collect();
+    words.sort_unstable();
+    words.dedup();
+    Ok(Arc::new(words))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_in_words() {
+        let options = Options {
+            mode: Mode::Passphrase,
+            separator: " ".into(),
+            ..Options::default()
+        };
+        let generated = generate(&options, None).unwrap();
+        let words: Vec<&str> = WORDS
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .collect();
+        assert_eq!(words.len(), 2048);
+        assert!(words.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(
+            generated
+                .secret
+                .split(' ')
+                .all(|word| words.contains(&word))
+        );
+        assert!((generated.entropy - 66.0).abs() < f64::EPSILON);
+    }
+}
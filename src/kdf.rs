@@ -0,0 +1,197 @@
+use age::armor::ArmoredReader;
+use std::{
+    io::Read,
+    path::Path,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::store::{is_armored, passphrase_key_path};
+
+/// Lowest work factor we ever suggest, matches what age measures with.
+const MIN_WORK_FACTOR: u8 = 10;
+/// Anything above is far beyond a sensible unlock time on current hardware.
+const MAX_WORK_FACTOR: u8 = 30;
+/// age accepts work factors up to its own target + 4, which is about 22 on a
+/// machine that takes a second for `2^18`. Never accept less than that.
+const DEFAULT_MAX_WORK_FACTOR: u8 = 22;
+
+/// The work factor measured for a target time, so the benchmark runs at most
+/// once per process.
+static MEASURED: Mutex<Option<(Duration, u8)>> = Mutex::new(None);
+
+/// How the scrypt work factor for passphrase encryption gets picked.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) enum KdfPolicy {
+    /// Let age pick a factor that takes about one second on this machine
+    #[default]
+    Auto,
+    /// Use `N = 2^log_n`
+    LogN(u8),
+    /// Pick the largest factor that unlocks within the given time
+    Target(Duration),
+}
+
+impl KdfPolicy {
+    /// Returns the log2 N to encrypt with, `None` leaves the choice to age.
+    ///
+    /// Resolving a target time runs a short benchmark the first time, which
+    /// is why only saving asks for it.
+    pub(crate) fn work_factor(self) -> Option<u8> {
+        match self {
+            Self::Auto => None,
+            Self::LogN(log_n) => Some(log_n),
+            Self::Target(target) => {
+                let mut measured = MEASURED.lock().unwrap_or_else(PoisonError::into_inner);
+                match *measured {
+                    Some((time, log_n)) if time == target => Some(log_n),
+                    _ => {
+                        let log_n = work_factor_for(target);
+                        *measured = Some((target, log_n));
+                        Some(log_n)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Like [`Self::work_factor`], but `None` for a target time that wasn't
+    /// measured yet instead of running the benchmark.
+    pub(crate) fn known_work_factor(self) -> Option<u8> {
+        match self {
+            Self::Target(target) => MEASURED
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .filter(|(time, _)| *time == target)
+                .map(|(_, log_n)| log_n),
+            policy => policy.work_factor(),
+        }
+    }
+
+    /// The highest work factor to accept when decrypting, `None` leaves the
+    /// choice to age. Never benchmarks, an unmeasured target time accepts
+    /// what any target of up to 16 times age's own does.
+    pub(crate) fn max_work_factor(self) -> Option<u8> {
+        match self {
+            Self::Auto => None,
+            policy => Some(max_work_factor(
+                policy.known_work_factor().unwrap_or(MIN_WORK_FACTOR),
+            )),
+        }
+    }
+}
+
+pub(crate) fn parse_work_factor(value: &str) -> Option<u8> {
+    value
+        .parse()
+        .ok()
+        .filter(|log_n| (MIN_WORK_FACTOR..=MAX_WORK_FACTOR).contains(log_n))
+}
+
+/// The highest work factor age should accept when decrypting, so vaults
+/// written with `log_n` can be opened again.
+fn max_work_factor(log_n: u8) -> u8 {
+    log_n.saturating_add(4).max(DEFAULT_MAX_WORK_FACTOR)
+}
+
+/// Time a single scrypt derivation with age's parameters (`r = 8, p = 1`).
+pub(crate) fn measure(log_n: u8) -> Duration {
+    let Ok(params) = scrypt::Params::new(log_n, 8, 1, 32) else {
+        return Duration::MAX;
+    };
+    let mut key = [0u8; 32];
+    let start = Instant::now();
+    let _ = scrypt::scrypt(b"pdpw", b"pdpw-bench-kdf", &params, &mut key);
+    start.elapsed()
+}
+
+/// Largest work factor which stays within `target`, extrapolated like age
+/// does: the cost of scrypt doubles with every step of log2 N.
+pub(crate) fn work_factor_for(target: Duration) -> u8 {
+    let mut log_n = MIN_WORK_FACTOR;
+    let mut duration = measure(log_n);
+    while duration.is_zero() && log_n < MAX_WORK_FACTOR {
+        log_n += 1;
+        duration = measure(log_n);
+    }
+    while duration * 2 <= target && log_n < MAX_WORK_FACTOR {
+        log_n += 1;
+        duration *= 2;
+    }
+    log_n
+}
+
+/// Reads the work factor from the header of a passphrase encrypted vault.
+pub(crate) fn scrypt_work_factor(encrypted: &[u8]) -> Option<u8> {
+    let mut dearmored = vec![];
+    let encrypted = if is_armored(encrypted) {
+        ArmoredReader::new(encrypted)
+            .read_to_end(&mut dearmored)
+            .ok()?;
+        dearmored.as_slice()
+    } else {
+        encrypted
+    };
+    let header_end = encrypted
+        .windows(4)
+        .position(|w| w == b"\n---")
+        .unwrap_or(encrypted.len());
+    String::from_utf8_lossy(&encrypted[..header_end])
+        .lines()
+        .find_map(|line| {
+            let mut args = line.strip_prefix("-> scrypt ")?.split_whitespace();
+            args.nth(1)?.parse().ok()
+        })
+}
+
+/// The work factor protecting a vault: the one of the vault itself, or for
+/// a vault shared with recipients, the one of its passphrase protected key.
+pub(crate) async fn vault_work_factor(pdpw_file: &Path) -> Option<u8> {
+    for path in [pdpw_file.to_path_buf(), passphrase_key_path(pdpw_file)] {
+        if let Ok(encrypted) = tokio::fs::read(path).await
+            && let Some(log_n) = scrypt_work_factor(&encrypted)
+        {
+            return Some(log_n);
+        }
+    }
+    None
+}
+
+/// Prints how long each work factor takes on this machine.
+pub(crate) fn bench(target: Duration) {
+    println!("log2 N      time");
+    let mut recommended = MIN_WORK_FACTOR;
+    for log_n in MIN_WORK_FACTOR..=MAX_WORK_FACTOR {
+        let duration = measure(log_n);
+        println!("{log_n:>6} {:>9.3}s", duration.as_secs_f64());
+        if duration <= target {
+            recommended = log_n;
+        } else {
+            break;
+        }
+    }
+    println!(
+        "\nHighest work factor unlocking within {} ms: {recommended}",
+        target.as_millis()
+    );
+    println!(
+        "Use it with --kdf-log-n {recommended} or `kdf_log_n = {recommended}` in the config file"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn measures_a_target_time_once() {
+        let policy = KdfPolicy::Target(Duration::from_millis(1));
+        assert_eq!(policy.known_work_factor(), None);
+        assert_eq!(policy.max_work_factor(), Some(DEFAULT_MAX_WORK_FACTOR));
+        let log_n = policy.work_factor();
+        assert!(log_n.is_some());
+        assert_eq!(policy.known_work_factor(), log_n);
+        assert_eq!(KdfPolicy::LogN(12).known_work_factor(), Some(12));
+        assert_eq!(KdfPolicy::Auto.max_work_factor(), None);
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::Context;
+use std::{
+    fmt::Display,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::io::AsyncWriteExt;
+
+const LOCK_SUFFIX: &str = ".lock";
+
+/// The instance holding the lock of a vault, as recorded in `<vault>.lock`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LockOwner {
+    pub(crate) pid: u32,
+    pub(crate) hostname: String,
+    pub(crate) since: Option<SystemTime>,
+}
+
+impl LockOwner {
+    fn current() -> Self {
+        Self {
+            pid: std::process::id(),
+            hostname: gethostname::gethostname().to_string_lossy().into_owned(),
+            since: None,
+        }
+    }
+
+    fn parse(data: &str, since: Option<SystemTime>) -> Self {
+        let mut lines = data.lines().map(str::trim);
+        Self {
+            pid: lines.next().and_then(|pid| pid.parse().ok()).unwrap_or(0),
+            hostname: lines.next().unwrap_or_default().to_string(),
+            since,
+        }
+    }
+
+    /// Only a lock taken on this machine by a process that is gone can be
+    /// told apart from a live one.
+    pub(crate) fn is_stale(&self) -> bool {
+        self.hostname == Self::current().hostname && !process_exists(self.pid)
+    }
+}
+
+impl Display for LockOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "process {} on {}", self.pid, self.hostname)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn process_exists(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_exists(_pid: u32) -> bool {
+    true
+}
+
+/// Advisory lock on a vault, released when dropped.
+///
+/// A plain lock file is used instead of `flock` as the latter isn't reliable
+/// on network file systems, which is where vaults are shared.
+#[derive(Debug)]
+pub(crate) struct VaultLock {
+    path: PathBuf,
+}
+
+impl Drop for VaultLock {
+    fn drop(&mut self) {
+        // the lock may have been broken and taken over by someone else
+        let owner = std::fs::read_to_string(&self.path).map(|data| LockOwner::parse(&data, None));
+        if owner.is_ok_and(|owner| owner == LockOwner::current()) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+pub(crate) fn lock_path(pdpw_file: &Path) -> PathBuf {
+    let mut name = pdpw_file.as_os_str().to_owned();
+    name.push(LOCK_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Takes the lock of `pdpw_file`, returns the current owner if it is held
+/// elsewhere. `force` breaks an existing lock.
+pub(crate) async fn lock_vault(
+    pdpw_file: &Path,
+    force: bool,
+) -> anyhow::Result<Result<VaultLock, LockOwner>> {
+    let path = lock_path(pdpw_file);
+    if force {
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("Couldn't remove {}", path.display()));
+            }
+            _ => {}
+        }
+    }
+    let owner = LockOwner::current();
+    match tokio::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .await
+    {
+        Ok(mut file) => {
+            file.write_all(format!("{}\n{}\n", owner.pid, owner.hostname).as_bytes())
+                .await?;
+            file.sync_all().await?;
+            Ok(Ok(VaultLock { path }))
+        }
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            let data = tokio::fs::read_to_string(&path)
+                .await
+                .with_context(|| format!("Couldn't read {}", path.display()))?;
+            let since = tokio::fs::metadata(&path)
+                .await
+                .and_then(|m| m.modified())
+                .ok();
+            Ok(Err(LockOwner::parse(&data, since)))
+        }
+        Err(e) => Err(e).with_context(|| format!("Couldn't create {}", path.display())),
+    }
+}
@@ -1,16 +1,31 @@
 #![windows_subsystem = "windows"]
 mod about;
+mod cli;
+mod clipboard;
+mod config;
 mod editor;
+mod entry;
 mod galloc;
+mod generate;
+mod kdf;
+mod lock;
+mod merge;
 mod modal;
+mod passphrase;
+mod recipients;
+mod search;
 mod store;
+mod totp;
+mod watch;
 
-use std::{io::IsTerminal, path::Path};
+use std::{io::IsTerminal, path::Path, process::ExitCode};
 
 use about::MsgPopup;
-use anyhow::{Context, anyhow};
+use cli::{Command, KeyfileChange};
+use config::Config;
 use editor::Editor;
 use galloc::SecureGlobalAlloc;
+use passphrase::{PASSPHRASE_CMD_ENV, PassphraseSource};
 
 #[global_allocator]
 static GA: galloc::SecureGlobalAlloc = SecureGlobalAlloc;
@@ -19,21 +34,72 @@ const DEFAULT_FILE_NAME: &str = "default.pdpw";
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 struct Cli {
+    command: Option<Command>,
     pdpw_file: String,
     skip_cleanup: bool,
+    config: Config,
 }
 
 impl Cli {
-    fn print_help(prog_name: &str, err: Option<&str>) {
+    fn print_help(prog_name: &str, err: Option<&str>) -> ! {
+        let backups = Config::default().backup_count;
+        let lock_after = Config::default().lock_after.map_or(0, |d| d.as_secs());
+        let clear_clipboard = Config::default()
+            .clear_clipboard_after
+            .map_or(0, |d| d.as_secs());
+        let config_file = Config::path().map_or_else(String::new, |p| p.display().to_string());
         let mut help_message = format!(
             r"
 Simple passvault application [v{VERSION}]
 
 Synopsis:
-    {prog_name} [OPTIONS] <path-to-pdpw-file>
+    {prog_name} [OPTIONS] [<path-to-pdpw-file>]
+    {prog_name} [OPTIONS] <COMMAND> [<path-to-pdpw-file>]
+
+Settings can also be stored in {config_file}
+
+Environment:
+    {PASSPHRASE_CMD_ENV}            Command whose stdout is the master password
+
+Commands:
+    cat                           Decrypt the vault to stdout
+    edit                          Edit the vault with $VISUAL or $EDITOR
+    grep <pattern>                Print all lines containing <pattern>
+    totp <entry>                  Print the current TOTP code of <entry>
+    passwd                        Change the master password
+    gen-keyfile <path>            Write a new random keyfile to <path>
+    bench-kdf                     Measure which scrypt work factor this machine
+                                  can afford
 
 Options:
     --skip-clipboard-cleanup      Do not cleanup OS clipboard on program exit
+    --backups <N>                 Number of previous vault versions to keep
+                                  as <file>.1 .. <file>.N (default {backups})
+    --passphrase-fd <N>           Read the master password from file descriptor N
+    --passphrase-file <PATH>      Read the master password from the file PATH
+    --pinentry                    Ask for the master password using pinentry
+                                  ($PINENTRY or `pinentry`)
+    --recipient <KEY>             Encrypt the vault to the age or SSH public KEY,
+                                  can be given multiple times
+    --recipients-file <PATH>      Encrypt the vault to all public keys in PATH
+    --identity <PATH>             Decrypt the vault with the age or SSH private
+                                  key in PATH, can be given multiple times
+    --keyfile <PATH>              Require the file PATH in addition to the
+                                  master password
+    --new-keyfile <PATH>          Protect the vault with the keyfile PATH after
+                                  passwd
+    --remove-keyfile              Drop the keyfile requirement on passwd
+    --break-lock                  Take over the lock of a vault opened elsewhere
+    --armor                       Save the vault as ASCII armored text
+    --no-armor                    Save the vault in binary form
+                                  (default: keep the format of the file)
+    --kdf-log-n <N>               Encrypt with the scrypt work factor 2^N
+    --kdf-target-ms <MS>          Pick the scrypt work factor so unlocking takes
+                                  about MS milliseconds on this machine
+    --lock-after-secs <SECS>      Lock the vault after SECS seconds without input,
+                                  0 never locks (default {lock_after})
+    --clear-clipboard-secs <SECS> Clear copied text from the clipboard after SECS
+                                  seconds, 0 keeps it (default {clear_clipboard})
     --help                        Print this message
 
 "
@@ -41,7 +107,7 @@ Options:
         if let Some(err_msg) = err.as_ref() {
             help_message = format!("{err_msg}\n\n{help_message}");
         }
-        if std::io::stdin().is_terminal() {
+        if std::io::stdin().is_terminal() || std::io::stderr().is_terminal() {
             println!("{help_message}");
         } else {
             let help = std::sync::Arc::new(help_message);
@@ -60,68 +126,204 @@ Options:
         }
     }
 
-    fn parse_arguments() -> anyhow::Result<Self> {
+    #[allow(clippy::too_many_lines)]
+    fn parse_arguments() -> Self {
         let args: Vec<String> = std::env::args().collect();
         let prog_name = args.first().map_or("pdpw", std::string::String::as_str);
-        if args.len() > 3 {
-            Cli::print_help(prog_name, Some("Error: Wrong number of arguments!"));
-        }
-        if args.iter().any(|v| v.contains("--help")) {
-            Cli::print_help(prog_name, None);
-        }
-        let skip_cleanup = args.iter().any(|v| v.contains("--skip-clipboard-cleanup"));
-        if !skip_cleanup && args.len() > 2 {
+        let mut skip_cleanup = false;
+        let mut keyfile_change = KeyfileChange::Keep;
+        let mut config = Config::load().unwrap_or_else(|e| {
             Cli::print_help(
                 prog_name,
-                Some(&format!("Error: Unexpected option {}", args[1])),
-            );
+                Some(&format!("Error: Invalid config file: {e:#}")),
+            )
+        });
+        let mut positional = vec![];
+        let mut rest = args.iter().skip(1);
+        while let Some(arg) = rest.next() {
+            match arg.as_str() {
+                "--help" => Cli::print_help(prog_name, None),
+                "--skip-clipboard-cleanup" => skip_cleanup = true,
+                "--backups" => match rest.next().and_then(|n| n.parse().ok()) {
+                    Some(count) => config.backup_count = count,
+                    None => Cli::print_help(prog_name, Some("Error: --backups expects a number!")),
+                },
+                option @ ("--kdf-log-n"
+                | "--kdf-target-ms"
+                | "--lock-after-secs"
+                | "--clear-clipboard-secs") => {
+                    let key = option.trim_start_matches("--").replace('-', "_");
+                    if let Err(e) = config.set(&key, rest.next().map_or("", String::as_str)) {
+                        Cli::print_help(prog_name, Some(&format!("Error: {e}!")));
+                    }
+                }
+                "--passphrase-fd" => match rest.next().and_then(|n| n.parse().ok()) {
+                    Some(fd) => config.passphrase = PassphraseSource::Fd(fd),
+                    None => Cli::print_help(
+                        prog_name,
+                        Some("Error: --passphrase-fd expects a file descriptor!"),
+                    ),
+                },
+                "--passphrase-file" => match rest.next() {
+                    Some(path) => config.passphrase = PassphraseSource::File(path.into()),
+                    None => {
+                        Cli::print_help(prog_name, Some("Error: --passphrase-file expects a path!"))
+                    }
+                },
+                "--break-lock" => config.break_lock = true,
+                "--armor" => config.armor = Some(true),
+                "--no-armor" => config.armor = Some(false),
+                "--pinentry" => config.passphrase = PassphraseSource::Pinentry,
+                "--recipient" => match rest.next() {
+                    Some(recipient) => config.recipients.push(recipient.clone()),
+                    None => {
+                        Cli::print_help(prog_name, Some("Error: --recipient expects a public key!"))
+                    }
+                },
+                "--recipients-file" => match rest.next().map(std::fs::read_to_string) {
+                    Some(Ok(recipients)) => config.recipients.extend(
+                        recipients
+                            .lines()
+                            .map(str::trim)
+                            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                            .map(str::to_string),
+                    ),
+                    Some(Err(e)) => Cli::print_help(
+                        prog_name,
+                        Some(&format!("Error: Couldn't read recipients file: {e}")),
+                    ),
+                    None => {
+                        Cli::print_help(prog_name, Some("Error: --recipients-file expects a path!"))
+                    }
+                },
+                "--identity" => match rest.next() {
+                    Some(path) => config.identities.push(path.into()),
+                    None => Cli::print_help(prog_name, Some("Error: --identity expects a path!")),
+                },
+                "--keyfile" => match rest.next() {
+                    Some(path) => config.keyfile = Some(path.into()),
+                    None => Cli::print_help(prog_name, Some("Error: --keyfile expects a path!")),
+                },
+                "--new-keyfile" => match rest.next() {
+                    Some(path) => keyfile_change = KeyfileChange::Set(path.into()),
+                    None => {
+                        Cli::print_help(prog_name, Some("Error: --new-keyfile expects a path!"))
+                    }
+                },
+                "--remove-keyfile" => keyfile_change = KeyfileChange::Remove,
+                option if option.starts_with("--") => Cli::print_help(
+                    prog_name,
+                    Some(&format!("Error: Unexpected option {option}")),
+                ),
+                value => positional.push(value.to_string()),
+            }
+        }
+        if matches!(config.passphrase, PassphraseSource::Prompt)
+            && let Ok(command) = std::env::var(PASSPHRASE_CMD_ENV)
+            && !command.trim().is_empty()
+        {
+            config.passphrase = PassphraseSource::Command(command);
+        }
+        if let Some(Err(e)) = config
+            .recipients
+            .iter()
+            .map(|r| store::parse_recipient(r))
+            .find(Result::is_err)
+        {
+            Cli::print_help(prog_name, Some(&format!("Error: {e}")));
+        }
+        let mut positional = positional.into_iter();
+        let command = match positional.as_slice().first().map(String::as_str) {
+            Some("cat") => Some(Command::Cat),
+            Some("edit") => Some(Command::Edit),
+            Some("passwd") => Some(Command::Passwd(keyfile_change)),
+            Some("bench-kdf") => Some(Command::BenchKdf),
+            Some("gen-keyfile") => {
+                positional.next();
+                match positional.next() {
+                    Some(path) => Some(Command::GenKeyfile(path.into())),
+                    None => Cli::print_help(prog_name, Some("Error: gen-keyfile expects a path!")),
+                }
+            }
+            Some("totp") => {
+                positional.next();
+                match positional.next() {
+                    Some(name) => Some(Command::Totp(name)),
+                    None => Cli::print_help(prog_name, Some("Error: totp expects an entry!")),
+                }
+            }
+            Some("grep") => {
+                positional.next();
+                match positional.next() {
+                    Some(pattern) => Some(Command::Grep(pattern)),
+                    None => Cli::print_help(prog_name, Some("Error: grep expects a pattern!")),
+                }
+            }
+            _ => None,
+        };
+        if matches!(
+            command,
+            Some(Command::Cat | Command::Edit | Command::Passwd(_) | Command::BenchKdf)
+        ) {
+            positional.next();
+        }
+        let pdpw_file = positional.next();
+        if positional.next().is_some() {
+            Cli::print_help(prog_name, Some("Error: Wrong number of arguments!"));
         }
-        let pdpw_file = if args.len() == 1 || skip_cleanup && args.len() == 2 {
+        let pdpw_file = pdpw_file.unwrap_or_else(|| {
             // use default pdpw file path
             dirs::home_dir()
                 .and_then(|p| {
                     let p = p.join(DEFAULT_FILE_NAME);
                     p.to_str().map(std::string::ToString::to_string)
                 })
-                .unwrap_or_else(|| "{DEFAULT_FILE_NAME}".to_string())
-        } else {
-            args.last().cloned().ok_or(anyhow!("Invalid pdpw file"))?
-        };
+                .unwrap_or_else(|| DEFAULT_FILE_NAME.to_string())
+        });
         if !Path::new(&pdpw_file)
             .extension()
             .is_some_and(|ext| ext.eq_ignore_ascii_case("pdpw"))
         {
             Cli::print_help(prog_name, Some("Error: Expected *.pdpw file!"));
         }
-        Ok(Self {
+        Self {
+            command,
             pdpw_file,
             skip_cleanup,
-        })
+            config,
+        }
     }
 }
 
-fn main() -> anyhow::Result<()> {
-    let args = Cli::parse_arguments()?;
+fn main() -> anyhow::Result<ExitCode> {
+    let args = Cli::parse_arguments();
+    if let Some(command) = &args.command {
+        return cli::run(command, Path::new(&args.pdpw_file), &args.config);
+    }
 
+    let pin = args.config.passphrase.read()?;
     let pdpw_file = std::sync::Arc::new(args.pdpw_file);
-    iced::application(
-        move || Editor::new(&pdpw_file.clone()),
+    let config = args.config;
+    let result = iced::application(
+        move || Editor::new(&pdpw_file.clone(), config.clone(), pin.clone()),
         Editor::update,
         Editor::view,
     )
     .title("PdPw - Your Personal Passvault")
     .subscription(Editor::subscription)
     .default_font(iced::Font::MONOSPACE)
-    .run()?;
+    // closing asks about unsaved changes first
+    .exit_on_close_request(false)
+    .run();
 
     if !args.skip_cleanup {
-        // clear clipboard at the end
-        let mut clipboard =
-            arboard::Clipboard::new().with_context(|| "Couldn't access the clipboard!")?;
-        clipboard
-            .clear()
-            .with_context(|| "Something went wrong while trying to clean the clipboard")?;
+        // clear clipboard at the end, also when the application failed, whose
+        // error is the one to report
+        if let Err(e) = clipboard::clear() {
+            eprintln!("Could not clear the clipboard: {e}");
+        }
     }
 
-    Ok(())
+    result?;
+    Ok(ExitCode::SUCCESS)
 }
@@ -0,0 +1,240 @@
+/// Line based three-way merge (diff3) of two versions derived from a common
+/// base.
+///
+/// Lines keep their line endings, so joining all lines of a merge gives back
+/// the exact text.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Hunk {
+    /// Lines both sides agree on, or changed on one side only
+    Resolved(Vec<String>),
+    /// Lines changed differently on both sides
+    Conflict {
+        mine: Vec<String>,
+        theirs: Vec<String>,
+    },
+}
+
+/// How to resolve a conflicting hunk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Choice {
+    Mine,
+    Theirs,
+    /// Mine followed by theirs
+    Both,
+}
+
+/// Largest LCS table built for the changed middle part, 16 MiB. Beyond it the
+/// part is left unmatched and merges as one hunk, a conflict if both sides
+/// changed it.
+const MAX_TABLE: usize = 4 << 20;
+
+fn lines(text: &str) -> Vec<&str> {
+    text.split_inclusive('\n').collect()
+}
+
+/// For every line of `a`, the index of the matching line in `b` according to
+/// their longest common subsequence.
+fn matches(a: &[&str], b: &[&str]) -> Vec<Option<usize>> {
+    let mut result = vec![None; a.len()];
+    // common prefix and suffix are matched directly, which keeps the table
+    // small for the usual case of a few edited lines
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    for (i, slot) in result.iter_mut().enumerate().take(prefix) {
+        *slot = Some(i);
+    }
+    for k in 0..suffix {
+        result[a.len() - 1 - k] = Some(b.len() - 1 - k);
+    }
+    let (a_mid, b_mid) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    if (a_mid.len() + 1).saturating_mul(b_mid.len() + 1) > MAX_TABLE {
+        return result;
+    }
+    let width = b_mid.len() + 1;
+    let mut table = vec![0u32; (a_mid.len() + 1) * width];
+    for i in (0..a_mid.len()).rev() {
+        for j in (0..b_mid.len()).rev() {
+            table[i * width + j] = if a_mid[i] == b_mid[j] {
+                table[(i + 1) * width + j + 1] + 1
+            } else {
+                table[(i + 1) * width + j].max(table[i * width + j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    while i < a_mid.len() && j < b_mid.len() {
+        if a_mid[i] == b_mid[j] {
+            result[prefix + i] = Some(prefix + j);
+            i += 1;
+            j += 1;
+        } else if table[(i + 1) * width + j] >= table[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    result
+}
+
+fn to_owned(lines: &[&str]) -> Vec<String> {
+    lines.iter().map(|line| (*line).to_string()).collect()
+}
+
+/// Merges the changes `mine` and `theirs` made to `base`.
+pub(crate) fn merge(base: &str, mine: &str, theirs: &str) -> Vec<Hunk> {
+    let (base, mine, theirs) = (lines(base), lines(mine), lines(theirs));
+    let to_mine = matches(&base, &mine);
+    let to_theirs = matches(&base, &theirs);
+
+    let mut hunks: Vec<Hunk> = vec![];
+    let mut push = |hunk: Hunk| match (hunks.last_mut(), hunk) {
+        (Some(Hunk::Resolved(last)), Hunk::Resolved(lines)) => last.extend(lines),
+        (_, Hunk::Resolved(lines)) if lines.is_empty() => {}
+        (_, hunk) => hunks.push(hunk),
+    };
+    let (mut b, mut m, mut t) = (0, 0, 0);
+    loop {
+        // next base line that is unchanged on both sides
+        let stable = (b..base.len()).find_map(|i| Some((i, to_mine[i]?, to_theirs[i]?)));
+        let (b_end, m_end, t_end) = stable.unwrap_or((base.len(), mine.len(), theirs.len()));
+        if (b_end, m_end, t_end) == (b, m, t) {
+            if stable.is_none() {
+                break;
+            }
+            push(Hunk::Resolved(vec![base[b].to_string()]));
+            (b, m, t) = (b + 1, m + 1, t + 1);
+            continue;
+        }
+        let (old, ours, other) = (&base[b..b_end], &mine[m..m_end], &theirs[t..t_end]);
+        push(if ours == old || ours == other {
+            Hunk::Resolved(to_owned(other))
+        } else if other == old {
+            Hunk::Resolved(to_owned(ours))
+        } else {
+            Hunk::Conflict {
+                mine: to_owned(ours),
+                theirs: to_owned(other),
+            }
+        });
+        (b, m, t) = (b_end, m_end, t_end);
+    }
+    hunks
+}
+
+/// Joins the hunks back into text, taking `choices` for the conflicts in
+/// order.
+pub(crate) fn resolve(hunks: &[Hunk], choices: &[Choice]) -> String {
+    let mut choices = choices.iter();
+    let mut text = String::new();
+    for hunk in hunks {
+        match hunk {
+            Hunk::Resolved(lines) => text.extend(lines.iter().map(String::as_str)),
+            Hunk::Conflict { mine, theirs } => {
+                let choice = choices.next().copied().unwrap_or(Choice::Both);
+                if choice != Choice::Theirs {
+                    text.extend(mine.iter().map(String::as_str));
+                }
+                if choice != Choice::Mine {
+                    text.extend(theirs.iter().map(String::as_str));
+                }
+            }
+        }
+    }
+    text
+}
+
+pub(crate) fn conflicts(hunks: &[Hunk]) -> usize {
+    hunks
+        .iter()
+        .filter(|hunk| matches!(hunk, Hunk::Conflict { .. }))
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "# Github\nuser: octocat\npassword: hunter2\n\n# Mail\nuser: me\n";
+
+    fn merged(mine: &str, theirs: &str) -> (String, usize) {
+        let hunks = merge(BASE, mine, theirs);
+        (resolve(&hunks, &[]), conflicts(&hunks))
+    }
+
+    #[test]
+    fn one_sided_edits() {
+        let changed = BASE.replace("hunter2", "correct horse");
+        assert_eq!(merged(&changed, BASE), (changed.clone(), 0));
+        assert_eq!(merged(BASE, &changed), (changed, 0));
+        assert_eq!(merged(BASE, BASE), (BASE.to_string(), 0));
+    }
+
+    #[test]
+    fn edits_on_both_sides() {
+        let mine = BASE.replace("hunter2", "mine");
+        let theirs = BASE.replace("user: me", "user: them");
+        let expected = mine.replace("user: me", "user: them");
+        assert_eq!(merged(&mine, &theirs), (expected, 0));
+    }
+
+    #[test]
+    fn identical_edits() {
+        let both = BASE.replace("hunter2", "same");
+        assert_eq!(merged(&both, &both), (both.clone(), 0));
+    }
+
+    #[test]
+    fn conflicting_edits() {
+        let mine = BASE.replace("hunter2", "mine");
+        let theirs = BASE.replace("hunter2", "theirs");
+        let hunks = merge(BASE, &mine, &theirs);
+        assert_eq!(conflicts(&hunks), 1);
+        assert!(hunks.contains(&Hunk::Conflict {
+            mine: vec!["password: mine\n".into()],
+            theirs: vec!["password: theirs\n".into()],
+        }));
+        assert_eq!(resolve(&hunks, &[Choice::Mine]), mine);
+        assert_eq!(resolve(&hunks, &[Choice::Theirs]), theirs);
+        assert_eq!(
+            resolve(&hunks, &[Choice::Both]),
+            BASE.replace("password: hunter2\n", "password: mine\npassword: theirs\n")
+        );
+    }
+
+    #[test]
+    fn inserts_at_eof() {
+        let mine = format!("{BASE}\n# Bank\npin: 1\n");
+        assert_eq!(merged(&mine, BASE), (mine.clone(), 0));
+        let theirs = format!("{BASE}\n# Wifi\npassword: guest\n");
+        let hunks = merge(BASE, &mine, &theirs);
+        assert_eq!(conflicts(&hunks), 1);
+        assert_eq!(
+            resolve(&hunks, &[Choice::Both]),
+            format!("{BASE}\n# Bank\npin: 1\n\n# Wifi\npassword: guest\n")
+        );
+        // without a trailing newline the last line changes on both sides
+        let base = BASE.trim_end();
+        let hunks = merge(base, &format!("{base}\nmine"), base);
+        assert_eq!(resolve(&hunks, &[]), format!("{base}\nmine"));
+    }
+
+    #[test]
+    fn huge_changes_fall_back_to_one_hunk() {
+        let text = |prefix: &str| {
+            (0..3000)
+                .map(|i| format!("{prefix}{i}\n"))
+                .collect::<Vec<_>>()
+                .concat()
+        };
+        let (base, mine, theirs) = (text("b"), text("m"), text("t"));
+        let hunks = merge(&base, &mine, &theirs);
+        assert_eq!(conflicts(&hunks), 1);
+        assert_eq!(resolve(&hunks, &[Choice::Theirs]), theirs);
+        assert_eq!(resolve(&merge(&base, &mine, &base), &[]), mine);
+    }
+}
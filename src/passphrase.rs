@@ -0,0 +1,220 @@
+use anyhow::{Context, bail};
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+/// Environment variable holding a command whose stdout is the passphrase.
+pub(crate) const PASSPHRASE_CMD_ENV: &str = "PDPW_PASSPHRASE_CMD";
+
+/// Where the master password comes from.
+#[derive(Debug, Clone, Default)]
+pub(crate) enum PassphraseSource {
+    /// Ask the user, either in the GUI or on the terminal
+    #[default]
+    Prompt,
+    /// Read the first line from an already open file descriptor
+    Fd(i32),
+    /// Read the first line of a file
+    File(PathBuf),
+    /// Run a shell command and use the first line of its stdout
+    Command(String),
+    /// Ask a `pinentry` program via the Assuan protocol
+    Pinentry,
+}
+
+impl PassphraseSource {
+    /// Returns the passphrase or `None` if the user has to be prompted.
+    pub(crate) fn read(&self) -> anyhow::Result<Option<String>> {
+        let passphrase = match self {
+            Self::Prompt => return Ok(None),
+            Self::Fd(fd) => first_line(&read_fd(*fd)?),
+            Self::File(path) => first_line(
+                &std::fs::read_to_string(path)
+                    .with_context(|| format!("Couldn't read passphrase file {}", path.display()))?,
+            ),
+            Self::Command(command) => first_line(&run_command(command)?),
+            Self::Pinentry => pinentry("Enter the master password of your pdpw vault")?,
+        };
+        if passphrase.is_empty() {
+            bail!("The passphrase must not be empty");
+        }
+        Ok(Some(passphrase))
+    }
+}
+
+fn first_line(s: &str) -> String {
+    s.lines().next().unwrap_or_default().to_string()
+}
+
+#[cfg(unix)]
+fn read_fd(fd: i32) -> anyhow::Result<String> {
+    use std::os::fd::FromRawFd;
+
+    if fd < 0 || fd == 1 || fd == 2 {
+        bail!("Invalid passphrase file descriptor {fd}");
+    }
+    // SAFETY: the descriptor was handed to us by the caller for exactly this
+    // purpose and is not used anywhere else in the program.
+    let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
+    let mut passphrase = String::new();
+    file.read_to_string(&mut passphrase)
+        .with_context(|| format!("Couldn't read passphrase from file descriptor {fd}"))?;
+    Ok(passphrase)
+}
+
+#[cfg(not(unix))]
+fn read_fd(_fd: i32) -> anyhow::Result<String> {
+    bail!("--passphrase-fd is only supported on unix systems")
+}
+
+fn run_command(command: &str) -> anyhow::Result<String> {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let output = Command::new(shell)
+        .args([flag, command])
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("Couldn't run ${PASSPHRASE_CMD_ENV}"))?;
+    if !output.status.success() {
+        bail!("${PASSPHRASE_CMD_ENV} exited with {}", output.status);
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Minimal client for the pinentry flavour of the Assuan protocol.
+fn pinentry(description: &str) -> anyhow::Result<String> {
+    let program = std::env::var("PINENTRY").unwrap_or_else(|_| "pinentry".to_string());
+    let mut child = Command::new(&program)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Couldn't start {program}"))?;
+    let mut input = child.stdin.take().context("pinentry has no stdin")?;
+    let mut output = BufReader::new(child.stdout.take().context("pinentry has no stdout")?);
+
+    let result = (|| {
+        read_response(&mut output)?;
+        for command in [
+            format!("SETDESC {}", assuan_encode(description)),
+            "SETPROMPT Password:".to_string(),
+            "SETTITLE pdpw".to_string(),
+        ] {
+            writeln!(input, "{command}")?;
+            read_response(&mut output)?;
+        }
+        writeln!(input, "GETPIN")?;
+        let pin = read_response(&mut output)?;
+        let _ = writeln!(input, "BYE");
+        Ok(pin)
+    })();
+    drop(input);
+    let _ = child.wait();
+    result
+}
+
+/// Reads until the final `OK`, returning the decoded data lines.
+fn read_response(output: &mut impl BufRead) -> anyhow::Result<String> {
+    let mut data = String::new();
+    loop {
+        let mut line = String::new();
+        if output.read_line(&mut line)? == 0 {
+            bail!("pinentry closed the connection");
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line == "OK" || line.starts_with("OK ") {
+            return Ok(data);
+        } else if let Some(payload) = line.strip_prefix("D ") {
+            data.push_str(&assuan_decode(payload));
+        } else if let Some(error) = line.strip_prefix("ERR ") {
+            bail!("pinentry: {error}");
+        }
+        // status (S) and comment (#) lines are ignored
+    }
+}
+
+fn assuan_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '%' => encoded.push_str("%25"),
+            '\n' => encoded.push_str("%0A"),
+            '\r' => encoded.push_str("%0D"),
+            c => encoded.push(c),
+        }
+    }
+    encoded
+}
+
+fn assuan_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(byte) = s
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn response(lines: &str) -> anyhow::Result<String> {
+        read_response(&mut lines.as_bytes())
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(assuan_encode("100% sure\r\nnext"), "100%25 sure%0D%0Anext");
+        assert_eq!(assuan_encode("plain ÄÖÜ"), "plain ÄÖÜ");
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(assuan_decode("100%25%0Anext%0d"), "100%\nnext\r");
+        // a stray percent sign is kept
+        assert_eq!(assuan_decode("50% off %zz %4"), "50% off %zz %4");
+        // multibyte characters, encoded or not
+        assert_eq!(assuan_decode("%C3%A4 ö"), "ä ö");
+        let password = "p%ss\nwörd\r";
+        assert_eq!(assuan_decode(&assuan_encode(password)), password);
+    }
+
+    #[test]
+    fn responses() {
+        assert_eq!(response("OK Pleased to meet you\n").unwrap(), "");
+        assert_eq!(
+            response("S PASSWORD_FROM_CACHE\nD hunter2\nOK\n").unwrap(),
+            "hunter2"
+        );
+        // long data is split over several lines
+        assert_eq!(
+            response("# comment\nD correct%20horse \r\nD battery%25staple\r\nOK\r\n").unwrap(),
+            "correct horse battery%staple"
+        );
+        let error = response("ERR 83886179 Operation cancelled <Pinentry>\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "pinentry: 83886179 Operation cancelled <Pinentry>"
+        );
+        assert!(response("D incomplete\n").is_err());
+    }
+}
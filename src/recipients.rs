@@ -0,0 +1,296 @@
+use anyhow::Context;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::store::{parse_recipient, write_atomically};
+
+const RECIPIENTS_SUFFIX: &str = ".recipients";
+const KNOWN_RECIPIENTS_DIR: &str = "pdpw/recipients";
+
+/// A public key the vault is encrypted to.
+///
+/// Recipients are kept next to the vault in `<vault>.recipients`, which is a
+/// plain age recipients file: one key per line, where a comment line right
+/// above a key holds its label.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Recipient {
+    pub(crate) key: String,
+    pub(crate) label: String,
+}
+
+impl Recipient {
+    /// `SHA256:...` fingerprint in the format `ssh-keygen -l` prints it for SSH
+    /// keys, a shortened key for native age keys.
+    pub(crate) fn fingerprint(&self) -> String {
+        let mut fields = self.key.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some(kind), Some(blob)) if kind.starts_with("ssh-") => {
+                let blob = base64::engine::general_purpose::STANDARD
+                    .decode(blob)
+                    .unwrap_or_else(|_| blob.as_bytes().to_vec());
+                let digest = Sha256::digest(blob);
+                format!(
+                    "SHA256:{}",
+                    base64::engine::general_purpose::STANDARD_NO_PAD.encode(digest)
+                )
+            }
+            (Some(key), _) if key.chars().count() > 20 => {
+                let head: String = key.chars().take(12).collect();
+                let tail = key
+                    .char_indices()
+                    .rev()
+                    .nth(7)
+                    .map_or(key, |(start, _)| &key[start..]);
+                format!("{head}…{tail}")
+            }
+            _ => self.key.clone(),
+        }
+    }
+
+    /// Keys are compared without the trailing SSH comment.
+    pub(crate) fn matches(&self, key: &str) -> bool {
+        let strip = |k: &str| k.split_whitespace().take(2).collect::<Vec<_>>().join(" ");
+        strip(&self.key) == strip(key)
+    }
+}
+
+/// Keys the recipients file gained or lost since the current user last saved
+/// the vault, e.g. because someone with write access to the shared directory
+/// added their own key.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct RecipientChanges {
+    pub(crate) added: Vec<Recipient>,
+    pub(crate) removed: Vec<Recipient>,
+}
+
+impl RecipientChanges {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+
+    fn between(known: &[Recipient], recipients: &[Recipient]) -> Self {
+        let missing = |from: &[Recipient], to: &[Recipient]| {
+            from.iter()
+                .filter(|r| !to.iter().any(|other| other.matches(&r.key)))
+                .cloned()
+                .collect()
+        };
+        Self {
+            added: missing(recipients, known),
+            removed: missing(known, recipients),
+        }
+    }
+}
+
+impl std::fmt::Display for RecipientChanges {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (sign, recipients) in [('+', &self.added), ('-', &self.removed)] {
+            for recipient in recipients {
+                writeln!(f, "{sign} {} {}", recipient.fingerprint(), recipient.label)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn recipients_path(pdpw_file: &Path) -> PathBuf {
+    let mut name = pdpw_file.as_os_str().to_owned();
+    name.push(RECIPIENTS_SUFFIX);
+    PathBuf::from(name)
+}
+
+/// Parses a recipients file, failing on the first line that isn't a valid
+/// public key.
+fn parse_recipients(path: &Path, data: &str) -> anyhow::Result<Vec<Recipient>> {
+    let mut recipients = vec![];
+    let mut label = String::new();
+    for (number, line) in data.lines().map(str::trim).enumerate() {
+        if let Some(comment) = line.strip_prefix('#') {
+            label = comment.trim().to_string();
+        } else if line.is_empty() {
+            label.clear();
+        } else {
+            parse_recipient(line)
+                .with_context(|| format!("{}, line {}", path.display(), number + 1))?;
+            recipients.push(Recipient {
+                key: line.to_string(),
+                label: std::mem::take(&mut label),
+            });
+        }
+    }
+    Ok(recipients)
+}
+
+/// Where the recipients the current user last saved the vault to are kept,
+/// in the local data directory instead of next to the vault where others
+/// could change them as well.
+fn known_recipients_path(pdpw_file: &Path) -> Option<PathBuf> {
+    // the same vault, however it is referred to
+    let dir = match pdpw_file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let vault = std::fs::canonicalize(dir)
+        .unwrap_or_else(|_| dir.to_path_buf())
+        .join(pdpw_file.file_name()?);
+    let name = Sha256::digest(vault.as_os_str().as_encoded_bytes())
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        });
+    dirs::data_local_dir().map(|dir| dir.join(KNOWN_RECIPIENTS_DIR).join(name))
+}
+
+/// Compares `recipients` with those the current user last saved the vault
+/// to. Nothing changed if the vault was never saved here.
+pub(crate) async fn recipient_changes(
+    pdpw_file: &Path,
+    recipients: &[Recipient],
+) -> anyhow::Result<RecipientChanges> {
+    let Some(path) = known_recipients_path(pdpw_file) else {
+        return Ok(RecipientChanges::default());
+    };
+    if !tokio::fs::try_exists(&path).await? {
+        return Ok(RecipientChanges::default());
+    }
+    let data = tokio::fs::read_to_string(&path)
+        .await
+        .with_context(|| format!("Couldn't read {}", path.display()))?;
+    let known = parse_recipients(&path, &data)?;
+    Ok(RecipientChanges::between(&known, recipients))
+}
+
+/// Remembers the recipients the vault got saved to, see [`recipient_changes`].
+pub(crate) async fn remember_recipients(
+    pdpw_file: &Path,
+    recipients: &[Recipient],
+) -> anyhow::Result<()> {
+    let Some(path) = known_recipients_path(pdpw_file) else {
+        return Ok(());
+    };
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("Couldn't create {}", dir.display()))?;
+    }
+    write_atomically(&path, format_recipients(recipients)?.as_bytes()).await
+}
+
+pub(crate) async fn load_recipients(pdpw_file: &Path) -> anyhow::Result<Vec<Recipient>> {
+    let path = recipients_path(pdpw_file);
+    if !tokio::fs::try_exists(&path).await? {
+        return Ok(vec![]);
+    }
+    let data = tokio::fs::read_to_string(&path)
+        .await
+        .with_context(|| format!("Couldn't read {}", path.display()))?;
+    parse_recipients(&path, &data)
+}
+
+/// Writes the recipients file, removing it once no recipients are left.
+pub(crate) async fn store_recipients(
+    pdpw_file: &Path,
+    recipients: &[Recipient],
+) -> anyhow::Result<()> {
+    let path = recipients_path(pdpw_file);
+    if recipients.is_empty() {
+        if tokio::fs::try_exists(&path).await? {
+            tokio::fs::remove_file(&path).await?;
+        }
+        return Ok(());
+    }
+    write_atomically(&path, format_recipients(recipients)?.as_bytes()).await
+}
+
+fn format_recipients(recipients: &[Recipient]) -> anyhow::Result<String> {
+    let mut data = String::new();
+    for recipient in recipients {
+        if !recipient.label.is_empty() {
+            writeln!(data, "# {}", recipient.label)?;
+        }
+        writeln!(data, "{}\n", recipient.key)?;
+    }
+    Ok(data)
+}
+
+/// Public keys belonging to the given identity files, i.e. the keys that
+/// grant the current user access.
+pub(crate) fn identity_recipients(identity_files: &[PathBuf]) -> Vec<String> {
+    let mut keys = vec![];
+    for path in identity_files {
+        let Ok(data) = std::fs::read_to_string(path) else {
+            continue;
+        };
+        if data.contains("AGE-SECRET-KEY-") {
+            keys.extend(
+                data.lines()
+                    .filter_map(|line| age::x25519::Identity::from_str(line.trim()).ok())
+                    .map(|identity| identity.to_public().to_string()),
+            );
+        } else if let Ok(identity) = age::ssh::Identity::from_buffer(data.as_bytes(), None)
+            && let Ok(recipient) = age::ssh::Recipient::try_from(identity)
+        {
+            keys.push(recipient.to_string());
+        }
+    }
+    keys
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    const AGE_KEY: &str = "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p";
+
+    fn recipient(key: &str) -> Recipient {
+        Recipient {
+            key: key.into(),
+            label: String::new(),
+        }
+    }
+
+    #[test]
+    fn fingerprint() {
+        assert_eq!(recipient(AGE_KEY).fingerprint(), "age1ql3z7hjy…aqmcac8p");
+        assert_eq!(recipient("short").fingerprint(), "short");
+        // hand edited garbage must not panic
+        let odd = "äöüäöüäöüäöüäöüäöüäöüäöü";
+        assert_eq!(recipient(odd).fingerprint(), "äöüäöüäöüäöü…öüäöüäöü");
+    }
+
+    #[test]
+    fn parse() {
+        let path = Path::new("vault.pdpw.recipients");
+        let recipients = parse_recipients(path, &format!("# Alice\n{AGE_KEY}\n\n")).unwrap();
+        assert_eq!(
+            recipients,
+            vec![Recipient {
+                key: AGE_KEY.into(),
+                label: "Alice".into(),
+            }]
+        );
+        let error = parse_recipients(path, &format!("{AGE_KEY}\nnot a key\n")).unwrap_err();
+        assert!(format!("{error:#}").starts_with("vault.pdpw.recipients, line 2:"));
+    }
+
+    #[test]
+    fn changes() {
+        let other =
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+        let known = [recipient(AGE_KEY)];
+        assert!(RecipientChanges::between(&known, &known).is_empty());
+        // the SSH comment doesn't make it a different key
+        let commented = [recipient(&format!("{other} bob@laptop"))];
+        let changes = RecipientChanges::between(&known, &commented);
+        assert_eq!(changes.added, commented);
+        assert_eq!(changes.removed, known);
+        assert!(RecipientChanges::between(&[recipient(other)], &commented).is_empty());
+    }
+}
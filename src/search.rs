@@ -0,0 +1,460 @@
+use anyhow::anyhow;
+use iced::advanced::text::highlighter;
+use regex::{Regex, RegexBuilder};
+use regex_syntax::ast::{self, Ast};
+use std::ops::Range;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// What `.` matches instead of a single character: a character with the
+/// marks, emoji modifiers and joined characters that follow it, close enough
+/// to a grapheme for matches to end on grapheme boundaries.
+const GRAPHEME: &str = r"(?:[^\p{M}\n](?:[\p{M}\x{FE0F}\x{1F3FB}-\x{1F3FF}]|\x{200D}[^\p{M}\n])*)";
+
+/// What the search dialog looks for.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct Query {
+    pub(crate) pattern: String,
+    pub(crate) case_sensitive: bool,
+    /// Take the pattern as regular expression instead of literal text
+    pub(crate) regex: bool,
+    /// Only match at word boundaries
+    pub(crate) whole_word: bool,
+}
+
+/// A match of the query, by line and byte range within the line.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Match {
+    pub(crate) line: usize,
+    pub(crate) range: Range<usize>,
+}
+
+/// Byte offsets of the `.` outside of classes in a parsed pattern.
+struct Dots(Vec<Range<usize>>);
+
+impl ast::Visitor for Dots {
+    type Output = Vec<Range<usize>>;
+    type Err = ();
+
+    fn finish(self) -> Result<Self::Output, ()> {
+        Ok(self.0)
+    }
+
+    fn visit_pre(&mut self, ast: &Ast) -> Result<(), ()> {
+        if let Ast::Dot(span) = ast {
+            self.0.push(span.start.offset..span.end.offset);
+        }
+        Ok(())
+    }
+}
+
+/// `pattern` with every `.` matching a whole grapheme.
+fn grapheme_dots(pattern: &str) -> anyhow::Result<String> {
+    let parsed = ast::parse::Parser::new()
+        .parse(pattern)
+        .map_err(|e| anyhow!("Invalid regular expression: {}", e.kind()))?;
+    let dots = ast::visit(&parsed, Dots(vec![])).unwrap_or_default();
+    let mut pattern = pattern.to_string();
+    for dot in dots.into_iter().rev() {
+        pattern.replace_range(dot, GRAPHEME);
+    }
+    Ok(pattern)
+}
+
+/// A compiled [`Query`].
+#[derive(Debug, Clone)]
+pub(crate) struct Matcher {
+    regex: Regex,
+    /// Whether the replacement refers to groups with `$1`
+    expand: bool,
+}
+
+impl Matcher {
+    pub(crate) fn new(query: &Query) -> anyhow::Result<Self> {
+        if query.pattern.is_empty() {
+            anyhow::bail!("Search string cannot be empty!");
+        }
+        let mut pattern = if query.regex {
+            grapheme_dots(&query.pattern)?
+        } else {
+            regex::escape(&query.pattern)
+        };
+        if query.whole_word {
+            pattern = format!(r"\b(?:{pattern})\b");
+        }
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(!query.case_sensitive)
+            .build()
+            .map_err(|e| match e {
+                regex::Error::CompiledTooBig(_) => {
+                    anyhow!("The regular expression is too big to search with")
+                }
+                e => anyhow!("Invalid regular expression: {e}"),
+            })?;
+        Ok(Self {
+            regex,
+            expand: query.regex,
+        })
+    }
+
+    /// All non-empty, non-overlapping matches in `line` as byte ranges.
+    ///
+    /// Matches start and end on grapheme boundaries, so `e` doesn't match
+    /// the base of a decomposed `é`.
+    pub(crate) fn find_iter<'a>(
+        &'a self,
+        line: &'a str,
+    ) -> impl Iterator<Item = Range<usize>> + 'a {
+        let mut boundaries = vec![false; line.len() + 1];
+        for (offset, _) in line.grapheme_indices(true) {
+            boundaries[offset] = true;
+        }
+        boundaries[line.len()] = true;
+        let mut start = 0;
+        std::iter::from_fn(move || {
+            while let Some(found) = self.regex.find_at(line, start) {
+                if !found.is_empty() && boundaries[found.start()] && boundaries[found.end()] {
+                    start = found.end();
+                    return Some(found.range());
+                }
+                // look again from the next grapheme, a shorter or later match
+                // may still fit
+                start = (found.start() + 1..=line.len()).find(|pos| boundaries[*pos])?;
+            }
+            None
+        })
+    }
+
+    /// The replacement for the match at `start` of `line`, with `$1` and
+    /// `${name}` referring to the groups of regular expressions.
+    pub(crate) fn expand(&self, line: &str, start: usize, replacement: &str) -> String {
+        let mut expanded = String::new();
+        match self.regex.captures_at(line, start) {
+            Some(captures) if self.expand => captures.expand(replacement, &mut expanded),
+            _ => expanded.push_str(replacement),
+        }
+        expanded
+    }
+
+    /// `line` with every match replaced, `None` if nothing matched.
+    pub(crate) fn replace(&self, line: &str, replacement: &str) -> Option<(String, usize)> {
+        let mut replaced = String::new();
+        let mut count = 0;
+        let mut last = 0;
+        for range in self.find_iter(line) {
+            replaced.push_str(&line[last..range.start]);
+            replaced.push_str(&self.expand(line, range.start, replacement));
+            last = range.end;
+            count += 1;
+        }
+        replaced.push_str(&line[last..]);
+        (count > 0).then_some((replaced, count))
+    }
+
+    /// All matches in `text`, line by line.
+    pub(crate) fn find_all(&self, text: &str) -> Vec<Match> {
+        text.split('\n')
+            .enumerate()
+            .flat_map(|(line, content)| {
+                self.find_iter(content)
+                    .map(move |range| Match { line, range })
+            })
+            .collect()
+    }
+}
+
+/// The match to select after the selection `from..to`, or before it when
+/// searching `backwards`. Continues at the other end of the text if there is
+/// none, which the returned flag tells.
+pub(crate) fn next_match(
+    matches: &[Match],
+    (from, to): ((usize, usize), (usize, usize)),
+    backwards: bool,
+    skip_current: bool,
+) -> Option<(&Match, bool)> {
+    let start = |found: &&Match| (found.line, found.range.start);
+    let next = if backwards {
+        matches.iter().rev().find(|found| start(found) < from)
+    } else {
+        let after = if skip_current { to } else { from };
+        matches.iter().find(|found| start(found) >= after)
+    };
+    match next {
+        Some(found) => Some((found, false)),
+        None if backwards => matches.last().map(|found| (found, true)),
+        None => matches.first().map(|found| (found, true)),
+    }
+}
+
+/// Highlights the matches of the search in the editor.
+pub(crate) struct Highlighter {
+    matcher: Option<Matcher>,
+    current_line: usize,
+}
+
+impl highlighter::Highlighter for Highlighter {
+    type Settings = Option<Query>;
+    type Highlight = ();
+    type Iterator<'a> = std::vec::IntoIter<(Range<usize>, ())>;
+
+    fn new(query: &Self::Settings) -> Self {
+        Self {
+            matcher: query.as_ref().and_then(|query| Matcher::new(query).ok()),
+            current_line: 0,
+        }
+    }
+
+    fn update(&mut self, query: &Self::Settings) {
+        *self = Self::new(query);
+    }
+
+    fn change_line(&mut self, line: usize) {
+        self.current_line = self.current_line.min(line);
+    }
+
+    fn highlight_line(&mut self, line: &str) -> Self::Iterator<'_> {
+        self.current_line += 1;
+        let Some(matcher) = &self.matcher else {
+            return Vec::new().into_iter();
+        };
+        matcher
+            .find_iter(line)
+            .map(|range| (range, ()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn current_line(&self) -> usize {
+        self.current_line
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn query(pattern: &str) -> Query {
+        Query {
+            pattern: pattern.into(),
+            ..Query::default()
+        }
+    }
+
+    fn regex(pattern: &str) -> Query {
+        Query {
+            regex: true,
+            ..query(pattern)
+        }
+    }
+
+    /// The matched text of every match in `line`.
+    fn found<'a>(query: &Query, line: &'a str) -> Vec<&'a str> {
+        let matcher = Matcher::new(query).unwrap();
+        matcher.find_iter(line).map(|range| &line[range]).collect()
+    }
+
+    #[test]
+    fn offsets_after_umlauts() {
+        let line = "Straße: Müllerstraße 5, Köln";
+        let matcher = Matcher::new(&query("Köln")).unwrap();
+        let ranges: Vec<_> = matcher.find_iter(line).collect();
+        assert_eq!(ranges, vec![line.find("Köln").unwrap()..line.len()]);
+    }
+
+    #[test]
+    fn case_insensitive_keeps_offsets() {
+        // lowercasing `İ` takes more bytes, which used to shift the offsets
+        let line = "İstanbul ÄRGER über Ärger";
+        assert_eq!(found(&query("ärger"), line), vec!["ÄRGER", "Ärger"]);
+        assert_eq!(found(&query("ÜBER"), line), vec!["über"]);
+        assert_eq!(found(&query("istanbul"), line), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn case_sensitive() {
+        let sensitive = Query {
+            case_sensitive: true,
+            ..query("Ärger")
+        };
+        assert_eq!(found(&sensitive, "ärger Ärger ÄRGER"), vec!["Ärger"]);
+    }
+
+    #[test]
+    fn greek_and_cyrillic() {
+        assert_eq!(
+            found(&query("σοφία"), "ΣΟΦΊΑ σοφία"),
+            vec!["ΣΟΦΊΑ", "σοφία"]
+        );
+        assert_eq!(
+            found(&query("пароль"), "Пароль: ПАРОЛЬ"),
+            vec!["Пароль", "ПАРОЛЬ"]
+        );
+    }
+
+    #[test]
+    fn cjk_and_emoji() {
+        let line = "密码: 秘密 👩‍💻 user: 山田";
+        assert_eq!(found(&query("秘密"), line), vec!["秘密"]);
+        assert_eq!(found(&regex("user: .."), line), vec!["user: 山田"]);
+        // the family emoji is a single grapheme of several characters
+        assert_eq!(found(&regex("秘密 . user"), line), vec!["秘密 👩‍💻 user"]);
+        assert_eq!(found(&query("👩"), line), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn combining_marks() {
+        // `é` as `e` followed by U+0301
+        let line = "cafe\u{301} latte";
+        assert_eq!(found(&query("cafe"), line), Vec::<&str>::new());
+        assert_eq!(found(&query("cafe\u{301}"), line), vec!["cafe\u{301}"]);
+        assert_eq!(found(&regex("caf."), line), vec!["cafe\u{301}"]);
+        // a match splitting `é` is skipped for the next one that doesn't
+        assert_eq!(found(&query("e"), line), vec!["e"]);
+        assert_eq!(found(&regex("[.e]"), line), vec!["e"]);
+        let whole_word = Query {
+            whole_word: true,
+            ..query("cafe\u{301}")
+        };
+        assert_eq!(found(&whole_word, line), vec!["cafe\u{301}"]);
+    }
+
+    #[test]
+    fn whole_word() {
+        let whole_word = Query {
+            whole_word: true,
+            ..query("straße")
+        };
+        assert_eq!(
+            found(&whole_word, "Straße Hauptstraße straßen"),
+            vec!["Straße"]
+        );
+    }
+
+    #[test]
+    fn unicode_classes() {
+        let line = "Müller, cafe\u{301}\t٤٢ Ωmega";
+        assert_eq!(
+            found(&regex(r"\w+"), line),
+            vec!["Müller", "cafe\u{301}", "٤٢", "Ωmega"]
+        );
+        // the same words `\b` finds
+        assert_eq!(found(&regex(r"\bMüller\b"), line), vec!["Müller"]);
+        assert_eq!(found(&regex(r"\d+"), line), vec!["٤٢"]);
+        assert_eq!(found(&regex(r"[^\w\s]"), line), vec![","]);
+        assert_eq!(found(&regex(r"\W+"), line), vec![", ", "\t", " "]);
+    }
+
+    #[test]
+    fn regex_over_hosts() {
+        let line = "url: https://vpn.corp.example.com, https://wiki.corp.example.com/ä";
+        assert_eq!(
+            found(&regex(r"[\w.-]+\.corp\.example\.com"), line),
+            vec!["vpn.corp.example.com", "wiki.corp.example.com"]
+        );
+    }
+
+    #[test]
+    fn invalid_regex() {
+        for pattern in ["(a", "a)", "*a", "[a", r"\q", "a{3,1}"] {
+            assert!(Matcher::new(&regex(pattern)).is_err(), "{pattern}");
+        }
+    }
+
+    #[test]
+    fn nested_repetition() {
+        // would backtrack exponentially
+        let line = "a".repeat(5_000);
+        assert!(found(&regex("(a*)*b"), &line).is_empty());
+        assert!(found(&regex("(ab)+c"), &"ab".repeat(5_000)).is_empty());
+        assert_eq!(found(&regex("(a|aa)+$"), &line), vec![line.as_str()]);
+    }
+
+    #[test]
+    fn long_lines() {
+        let line = format!("{}b", "a".repeat(5_000));
+        assert_eq!(found(&regex("a+b"), &line), vec![line.as_str()]);
+        assert_eq!(found(&regex(".*"), &line), vec![line.as_str()]);
+        assert_eq!(found(&regex("a*?b"), &line), vec![line.as_str()]);
+        assert_eq!(found(&regex("(ab)+"), &"ab".repeat(50)).len(), 1);
+    }
+
+    #[test]
+    fn replace_keeps_surroundings() {
+        let matcher = Matcher::new(&query("geheim")).unwrap();
+        assert_eq!(
+            matcher.replace("Passwort: GEHEIM, alt: geheim – ünd", "neu"),
+            Some(("Passwort: neu, alt: neu – ünd".into(), 2))
+        );
+        assert_eq!(matcher.replace("nichts", "neu"), None);
+        // literal text doesn't refer to groups
+        let matcher = Matcher::new(&query("alt")).unwrap();
+        assert_eq!(matcher.replace("alt", "$1"), Some(("$1".into(), 1)));
+    }
+
+    #[test]
+    fn replace_with_groups() {
+        let matcher = Matcher::new(&regex(r"(\w+)@(?<host>[\w.]+)")).unwrap();
+        assert_eq!(
+            matcher.replace("mail: jörg@example.com", "${host}/$1"),
+            Some(("mail: example.com/jörg".into(), 1))
+        );
+        let line = "user: ä, user: ö";
+        let matcher = Matcher::new(&regex(r"user: (.)")).unwrap();
+        assert_eq!(matcher.expand(line, 9, "login: $1"), "login: ö");
+    }
+
+    #[test]
+    fn matches_by_line() {
+        let matcher = Matcher::new(&query("ö")).unwrap();
+        let candidates = matcher.find_all("Öl\nkein\nschön öde");
+        assert_eq!(
+            candidates,
+            vec![
+                Match {
+                    line: 0,
+                    range: 0..2
+                },
+                Match {
+                    line: 2,
+                    range: 3..5
+                },
+                Match {
+                    line: 2,
+                    range: 7..9
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn search_wraps_around() {
+        let matcher = Matcher::new(&query("ü")).unwrap();
+        let candidates = matcher.find_all("über\nnichts\nmüde");
+        let at = |found: Option<(&Match, bool)>| found.map(|(m, wrapped)| (m.line, wrapped));
+        // forward from the end of the last match
+        assert_eq!(
+            at(next_match(&candidates, ((2, 1), (2, 3)), false, true)),
+            Some((0, true))
+        );
+        // forward from the first match
+        assert_eq!(
+            at(next_match(&candidates, ((0, 0), (0, 2)), false, true)),
+            Some((2, false))
+        );
+        // a selected match is found again unless skipped
+        assert_eq!(
+            at(next_match(&candidates, ((0, 0), (0, 2)), false, false)),
+            Some((0, false))
+        );
+        // backwards from the first match
+        assert_eq!(
+            at(next_match(&candidates, ((0, 0), (0, 2)), true, true)),
+            Some((2, true))
+        );
+        assert_eq!(
+            at(next_match(&candidates, ((2, 1), (2, 3)), true, true)),
+            Some((0, false))
+        );
+        assert_eq!(next_match(&[], ((0, 0), (0, 0)), false, true), None);
+    }
+}
@@ -1,23 +1,234 @@
-use age::secrecy::SecretString;
+use age::armor::{ArmoredReader, ArmoredWriter, Format};
+use age::secrecy::{ExposeSecret, SecretString};
+use anyhow::{Context, bail};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
 use std::{
-    io::{Read, Write},
+    ffi::OsString,
+    fmt::Write as _,
+    io::{BufReader, Read, Write},
     iter,
-    path::Path,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
 };
 
+use crate::kdf::{KdfPolicy, scrypt_work_factor};
+
 const PDPW_EXTENSION: &str = "pdpw";
+const PASSPHRASE_KEY_SUFFIX: &str = ".passphrase.age";
+const ARMOR_BEGIN: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+const KEYFILE_SIZE: usize = 64;
+
+/// Everything needed to encrypt and decrypt a vault.
+///
+/// Without recipients the vault is encrypted with the `pin` only. With
+/// recipients it is encrypted to those age / SSH public keys and, if a `pin` is
+/// set, additionally to a passphrase protected key stored next to the vault as
+/// `<vault>.passphrase.age`, so the pin keeps working as a fallback.
+///
+/// With a `keyfile` the pin is bound to the contents of that file, so neither
+/// the pin nor the keyfile alone unlocks the vault.
+#[derive(Clone)]
+pub(crate) struct Keys {
+    pub(crate) pin: String,
+    pub(crate) keyfile: Option<PathBuf>,
+    pub(crate) recipients: Vec<String>,
+    pub(crate) identity_files: Vec<PathBuf>,
+    /// How to pick the scrypt work factor for the pin
+    pub(crate) kdf: KdfPolicy,
+    /// The keys before changing the pin or keyfile, which still open the
+    /// passphrase protected key on disk.
+    pub(crate) previous: Option<Box<Keys>>,
+}
+
+impl Keys {
+    /// The secret scrypt is run on: the pin, combined with the SHA-256 of the
+    /// keyfile if one is set.
+    fn passphrase(&self) -> anyhow::Result<SecretString> {
+        let Some(keyfile) = &self.keyfile else {
+            return Ok(SecretString::from(self.pin.as_str()));
+        };
+        if self.pin.is_empty() {
+            bail!("A keyfile only works together with a master password");
+        }
+        let data = std::fs::read(keyfile)
+            .with_context(|| format!("Couldn't read keyfile {}", keyfile.display()))?;
+        let digest = Sha256::digest(&data)
+            .iter()
+            .fold(String::new(), |mut hex, byte| {
+                let _ = write!(hex, "{byte:02x}");
+                hex
+            });
+        Ok(SecretString::from(format!("{}\n{digest}", self.pin)))
+    }
+}
+
+/// Writes random bytes to a new keyfile, refusing to overwrite one.
+pub(crate) fn generate_keyfile(path: &Path) -> anyhow::Result<()> {
+    let mut data = [0u8; KEYFILE_SIZE];
+    rand::rngs::OsRng.fill_bytes(&mut data);
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Couldn't create keyfile {}", path.display()))?;
+    file.write_all(&data)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+pub(crate) fn parse_recipient(recipient: &str) -> anyhow::Result<Box<dyn age::Recipient + Send>> {
+    let recipient = recipient.trim();
+    if recipient.starts_with("age1") {
+        age::x25519::Recipient::from_str(recipient)
+            .map(|r| Box::new(r) as _)
+            .map_err(|e| anyhow::anyhow!("Invalid age recipient {recipient}: {e}"))
+    } else if recipient.starts_with("ssh-") {
+        age::ssh::Recipient::from_str(recipient)
+            .map(|r| Box::new(r) as _)
+            .map_err(|e| anyhow::anyhow!("Invalid SSH recipient {recipient}: {e:?}"))
+    } else {
+        bail!("Unsupported recipient {recipient}")
+    }
+}
+
+fn parse_identity_file(path: &Path, data: &str) -> anyhow::Result<Vec<Box<dyn age::Identity>>> {
+    if data.contains("AGE-SECRET-KEY-") {
+        return age::IdentityFile::from_buffer(data.as_bytes())?
+            .into_identities()
+            .with_context(|| format!("Invalid identity file {}", path.display()));
+    }
+    let identity = age::ssh::Identity::from_buffer(
+        BufReader::new(data.as_bytes()),
+        Some(path.display().to_string()),
+    )
+    .with_context(|| format!("Invalid identity file {}", path.display()))?;
+    match identity {
+        age::ssh::Identity::Unencrypted(_) => Ok(vec![Box::new(identity)]),
+        age::ssh::Identity::Encrypted(_) => bail!(
+            "Passphrase protected SSH keys are not supported: {}",
+            path.display()
+        ),
+        age::ssh::Identity::Unsupported(_) => {
+            bail!("Unsupported SSH key type: {}", path.display())
+        }
+    }
+}
+
+pub(crate) fn passphrase_key_path(pdpw_file: &Path) -> PathBuf {
+    let mut name = pdpw_file.as_os_str().to_owned();
+    name.push(PASSPHRASE_KEY_SUFFIX);
+    PathBuf::from(name)
+}
+
+async fn load_passphrase_key(
+    pdpw_file: &Path,
+    keys: &Keys,
+) -> anyhow::Result<Option<age::x25519::Identity>> {
+    let path = passphrase_key_path(pdpw_file);
+    if keys.pin.is_empty() || !tokio::fs::try_exists(&path).await? {
+        return Ok(None);
+    }
+    let encrypted = tokio::fs::read(&path).await?;
+    let key = decrypt_with_pin(&encrypted, keys)?;
+    let identity = age::x25519::Identity::from_str(key.trim())
+        .map_err(|e| anyhow::anyhow!("Invalid key in {}: {e}", path.display()))?;
+    Ok(Some(identity))
+}
+
+/// What saving a vault encrypted to recipients does with its passphrase
+/// protected key.
+enum PassphraseKey {
+    /// There is no key and no pin to create one with
+    None,
+    /// The key on disk, opened with the pin
+    Unchanged(age::x25519::Identity),
+    /// A new key, written before the vault that is encrypted to it
+    Create(age::x25519::Identity, Vec<u8>),
+    /// The existing key wrapped with a new pin, written after the vault
+    Rewrap(age::x25519::Identity, Vec<u8>),
+    /// The pin got removed, the key on disk opens nothing anymore
+    Remove,
+}
+
+/// Decides what happens with the passphrase protected key of the vault.
+///
+/// The key on disk is only replaced or dropped if it was opened with the pin
+/// entered, or the one before a pin change. Everyone else would lock out
+/// those who rely on the pin, so saving fails for them instead.
+async fn passphrase_key(pdpw_file: &Path, keys: &Keys) -> anyhow::Result<PassphraseKey> {
+    let path = passphrase_key_path(pdpw_file);
+    if !tokio::fs::try_exists(&path).await? {
+        if keys.pin.is_empty() {
+            return Ok(PassphraseKey::None);
+        }
+        let identity = age::x25519::Identity::generate();
+        let encrypted = wrap_passphrase_key(&identity, keys)?;
+        return Ok(PassphraseKey::Create(identity, encrypted));
+    }
+    // wrapped again when weaker than the policy, like the vault itself
+    let weak = scrypt_work_factor(&tokio::fs::read(&path).await?)
+        .zip(keys.kdf.work_factor())
+        .is_some_and(|(log_n, policy)| log_n < policy);
+    let error = match load_passphrase_key(pdpw_file, keys).await {
+        Ok(Some(identity)) if weak => {
+            let encrypted = wrap_passphrase_key(&identity, keys)?;
+            return Ok(PassphraseKey::Rewrap(identity, encrypted));
+        }
+        Ok(Some(identity)) => return Ok(PassphraseKey::Unchanged(identity)),
+        Ok(None) => None,
+        Err(e) => Some(e),
+    };
+    // keeping the key lets backups be opened with the new pin as well
+    if let Some(previous) = &keys.previous
+        && let Ok(Some(identity)) = load_passphrase_key(pdpw_file, previous).await
+    {
+        if keys.pin.is_empty() {
+            return Ok(PassphraseKey::Remove);
+        }
+        let encrypted = wrap_passphrase_key(&identity, keys)?;
+        return Ok(PassphraseKey::Rewrap(identity, encrypted));
+    }
+    match error {
+        Some(e) => Err(e).with_context(|| {
+            format!(
+                "The master password doesn't open {}, saving would lock out everyone using it",
+                path.display()
+            )
+        }),
+        None => bail!(
+            "This vault can also be opened with a master password, enter it to save the vault \
+             without removing that"
+        ),
+    }
+}
+
+fn wrap_passphrase_key(identity: &age::x25519::Identity, keys: &Keys) -> anyhow::Result<Vec<u8>> {
+    encrypt(
+        passphrase_encryptor(keys)?,
+        identity.to_string().expose_secret(),
+        false,
+    )
+}
+
+/// SHA-256 of the encrypted vault as stored on disk, `None` if there is no
+/// vault yet. Used to notice changes made behind our back.
+pub(crate) async fn vault_digest(pdpw_file: &Path) -> anyhow::Result<Option<[u8; 32]>> {
+    match tokio::fs::read(pdpw_file).await {
+        Ok(encrypted) => Ok(Some(Sha256::digest(encrypted).into())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Couldn't read {}", pdpw_file.display())),
+    }
+}
 
-pub(crate) async fn load_pdpw_file(pdpw_file: &Path, pin: &str) -> anyhow::Result<String> {
+pub(crate) async fn load_pdpw_file(pdpw_file: &Path, keys: &Keys) -> anyhow::Result<String> {
     let passwords = if pdpw_file.extension().is_some_and(|e| e == PDPW_EXTENSION) {
         if pdpw_file.exists() {
             let encrypted = tokio::fs::read(pdpw_file).await?;
-            let decryptor = age::Decryptor::new_async_buffered(encrypted.as_slice()).await?;
-            let mut decrypted = vec![];
-            let secret = SecretString::from(pin);
-            let mut reader =
-                decryptor.decrypt_async(iter::once(&age::scrypt::Identity::new(secret) as _))?;
-            reader.read_to_end(&mut decrypted)?;
-            String::from_utf8(decrypted)?
+            decrypt(pdpw_file, &encrypted, keys).await?
         } else {
             String::new()
         }
@@ -28,20 +239,598 @@ pub(crate) async fn load_pdpw_file(pdpw_file: &Path, pin: &str) -> anyhow::Resul
     Ok(passwords)
 }
 
+fn passphrase_encryptor(keys: &Keys) -> anyhow::Result<age::Encryptor> {
+    let mut recipient = age::scrypt::Recipient::new(keys.passphrase()?);
+    if let Some(log_n) = keys.kdf.work_factor() {
+        recipient.set_work_factor(log_n);
+    }
+    Ok(age::Encryptor::with_recipients(iter::once(&recipient as _))
+        .expect("a single scrypt recipient is always valid"))
+}
+
+fn decrypt_with_pin(encrypted: &[u8], keys: &Keys) -> anyhow::Result<String> {
+    let decryptor = age::Decryptor::new_buffered(ArmoredReader::new(encrypted))?;
+    let mut decrypted = vec![];
+    let mut identity = age::scrypt::Identity::new(keys.passphrase()?);
+    if let Some(log_n) = keys.kdf.max_work_factor() {
+        identity.set_max_work_factor(log_n);
+    }
+    let mut reader = decryptor.decrypt(iter::once(&identity as _))?;
+    reader.read_to_end(&mut decrypted)?;
+    Ok(String::from_utf8(decrypted)?)
+}
+
+/// Decrypts a vault (or one of its backups) belonging to `pdpw_file`.
+async fn decrypt(pdpw_file: &Path, encrypted: &[u8], keys: &Keys) -> anyhow::Result<String> {
+    decrypt_with_key(pdpw_file, encrypted, keys, None).await
+}
+
+/// Like [`decrypt`], with `passphrase_key` in place of the one stored on disk.
+async fn decrypt_with_key(
+    pdpw_file: &Path,
+    encrypted: &[u8],
+    keys: &Keys,
+    passphrase_key: Option<&age::x25519::Identity>,
+) -> anyhow::Result<String> {
+    let decryptor = age::Decryptor::new_buffered(ArmoredReader::new(encrypted))?;
+    if decryptor.is_scrypt() {
+        if keys.pin.is_empty() {
+            bail!("This vault is protected by a password");
+        }
+        return decrypt_with_pin(encrypted, keys);
+    }
+    let mut identity_files = vec![];
+    for path in &keys.identity_files {
+        let data = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Couldn't read identity file {}", path.display()))?;
+        identity_files.push((path, data));
+    }
+    // a wrong pin must not keep the identity files from opening the vault
+    let (passphrase_key, key_error) = match passphrase_key {
+        Some(identity) => (Some(identity.clone()), None),
+        None => match load_passphrase_key(pdpw_file, keys).await {
+            Ok(identity) => (identity, None),
+            Err(e) => (None, Some(e)),
+        },
+    };
+    // age identities are not `Send`, so no more awaits from here on
+    let mut identities = vec![];
+    for (path, data) in &identity_files {
+        identities.extend(parse_identity_file(path, data)?);
+    }
+    if let Some(identity) = passphrase_key {
+        identities.push(Box::new(identity) as Box<dyn age::Identity>);
+    }
+    if identities.is_empty() {
+        if let Some(e) = key_error {
+            return Err(e);
+        }
+        bail!("This vault is encrypted to recipients, an identity file is required");
+    }
+    let mut decrypted = vec![];
+    let mut reader = decryptor.decrypt(identities.iter().map(|i| i.as_ref() as _))?;
+    reader.read_to_end(&mut decrypted)?;
+    Ok(String::from_utf8(decrypted)?)
+}
+
+fn encrypt(encryptor: age::Encryptor, passwords: &str, armor: bool) -> anyhow::Result<Vec<u8>> {
+    let format = if armor {
+        Format::AsciiArmor
+    } else {
+        Format::Binary
+    };
+    let mut encrypted = vec![];
+    let mut writer = encryptor.wrap_output(ArmoredWriter::wrap_output(&mut encrypted, format)?)?;
+    writer.write_all(passwords.as_bytes())?;
+    writer.finish()?.finish()?;
+    Ok(encrypted)
+}
+
+/// Whether the vault is stored in age's PEM style ASCII armor.
+pub(crate) fn is_armored(encrypted: &[u8]) -> bool {
+    encrypted.trim_ascii_start().starts_with(ARMOR_BEGIN)
+}
+
+/// A previous version of a vault kept next to it as `<vault>.<n>`, where
+/// `n == 1` is the most recent one.
+#[derive(Debug, Clone)]
+pub(crate) struct Backup {
+    pub(crate) path: PathBuf,
+    pub(crate) modified: Option<SystemTime>,
+    pub(crate) size: u64,
+}
+
+fn backup_path(pdpw_file: &Path, index: usize) -> PathBuf {
+    let mut name = pdpw_file.as_os_str().to_owned();
+    name.push(format!(".{index}"));
+    PathBuf::from(name)
+}
+
+/// Lists the existing backups of `pdpw_file`, newest first.
+pub(crate) async fn list_backups(pdpw_file: &Path) -> anyhow::Result<Vec<Backup>> {
+    let mut backups = vec![];
+    for index in 1.. {
+        let path = backup_path(pdpw_file, index);
+        let Ok(metadata) = tokio::fs::metadata(&path).await else {
+            break;
+        };
+        backups.push(Backup {
+            path,
+            modified: metadata.modified().ok(),
+            size: metadata.len(),
+        });
+    }
+    Ok(backups)
+}
+
+pub(crate) async fn load_backup(
+    pdpw_file: &Path,
+    backup: &Path,
+    keys: &Keys,
+) -> anyhow::Result<String> {
+    let encrypted = tokio::fs::read(backup)
+        .await
+        .with_context(|| format!("Couldn't read backup {}", backup.display()))?;
+    // backups made before the passphrase key was rotated with them have none
+    if tokio::fs::try_exists(passphrase_key_path(backup)).await? {
+        decrypt(backup, &encrypted, keys).await
+    } else {
+        decrypt(pdpw_file, &encrypted, keys).await
+    }
+}
+
+/// Shifts `<vault>.1 .. <vault>.<keep - 1>` up by one, dropping the oldest,
+/// and copies the current vault to `<vault>.1`. The passphrase protected key
+/// is kept along as `<vault>.<n>.passphrase.age`, so every backup can still
+/// be opened with the pin it was saved with.
+async fn rotate_backups(pdpw_file: &Path, keep: usize) -> anyhow::Result<()> {
+    // the oldest one, and any left over from keeping more backups before
+    let mut index = keep.max(1);
+    loop {
+        let backup = backup_path(pdpw_file, index);
+        let key = passphrase_key_path(&backup);
+        let (has_backup, has_key) = (
+            tokio::fs::try_exists(&backup).await?,
+            tokio::fs::try_exists(&key).await?,
+        );
+        if !has_backup && !has_key {
+            break;
+        }
+        for (exists, path) in [(has_key, key), (has_backup, backup)] {
+            if exists {
+                tokio::fs::remove_file(&path)
+                    .await
+                    .with_context(|| format!("Couldn't remove old backup {}", path.display()))?;
+            }
+        }
+        index += 1;
+    }
+    if keep == 0 || !tokio::fs::try_exists(pdpw_file).await? {
+        return Ok(());
+    }
+    for index in (1..keep).rev() {
+        let from = backup_path(pdpw_file, index);
+        let to = backup_path(pdpw_file, index + 1);
+        for (from, to) in [
+            (passphrase_key_path(&from), passphrase_key_path(&to)),
+            (from, to),
+        ] {
+            if tokio::fs::try_exists(&from).await? {
+                tokio::fs::rename(&from, to).await?;
+            }
+        }
+    }
+    let newest = backup_path(pdpw_file, 1);
+    copy_synced(pdpw_file, &newest)
+        .await
+        .with_context(|| format!("Couldn't create backup {}", newest.display()))?;
+    let key = passphrase_key_path(pdpw_file);
+    if tokio::fs::try_exists(&key).await? {
+        let backup_key = passphrase_key_path(&newest);
+        copy_synced(&key, &backup_key)
+            .await
+            .with_context(|| format!("Couldn't create backup {}", backup_key.display()))?;
+    }
+    Ok(())
+}
+
+/// Copies `from` to `to` and fsyncs the copy. The directory entry is synced
+/// along with the vault replaced right after.
+async fn copy_synced(from: &Path, to: &Path) -> anyhow::Result<()> {
+    tokio::fs::copy(from, to).await?;
+    // flushing needs write access on Windows
+    tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(to)
+        .await?
+        .sync_all()
+        .await?;
+    Ok(())
+}
+
 pub(crate) async fn store_pdpw_file(
     pdpw_file: &Path,
-    pin: &str,
+    keys: &Keys,
     passwords: &str,
+    backups: usize,
+    armor: Option<bool>,
 ) -> anyhow::Result<()> {
-    let encrypted = {
-        let secret = SecretString::from(pin);
-        let encryptor = age::Encryptor::with_user_passphrase(secret);
-        let mut encrypted = vec![];
-        let mut writer = encryptor.wrap_async_output(&mut encrypted).await?;
-        writer.write_all(passwords.as_bytes())?;
-        writer.finish()?;
-        encrypted
-    };
-    tokio::fs::write(pdpw_file, encrypted).await?;
+    let mut passphrase = PassphraseKey::None;
+    let encryptor = if keys.recipients.is_empty() {
+        passphrase_encryptor(keys)?
+    } else {
+        let mut recipients = keys
+            .recipients
+            .iter()
+            .map(|r| parse_recipient(r))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        passphrase = passphrase_key(pdpw_file, keys).await?;
+        if let PassphraseKey::Unchanged(identity)
+        | PassphraseKey::Create(identity, _)
+        | PassphraseKey::Rewrap(identity, _) = &passphrase
+        {
+            recipients.push(Box::new(identity.to_public()));
+        }
+        age::Encryptor::with_recipients(recipients.iter().map(|r| r.as_ref() as _))?
+    };
+    // keep the format of an existing vault unless told otherwise
+    let armor = match armor {
+        Some(armor) => armor,
+        None => tokio::fs::read(pdpw_file)
+            .await
+            .is_ok_and(|encrypted| is_armored(&encrypted)),
+    };
+    let encrypted = encrypt(encryptor, passwords, armor)?;
+    // never commit a vault that we are not able to open again
+    let new_key = match &passphrase {
+        PassphraseKey::Create(identity, _) | PassphraseKey::Rewrap(identity, _) => Some(identity),
+        PassphraseKey::None | PassphraseKey::Unchanged(_) | PassphraseKey::Remove => None,
+    };
+    let round_trip = decrypt_with_key(pdpw_file, &encrypted, keys, new_key)
+        .await
+        .context("Verification of the encrypted vault failed, you would not be able to open it")?;
+    if round_trip != passwords {
+        anyhow::bail!("Verification of the encrypted vault failed: content mismatch");
+    }
+    rotate_backups(pdpw_file, backups).await?;
+    // a new key has to be on disk before any vault is encrypted to it, the
+    // old vault doesn't use it
+    if let PassphraseKey::Create(_, key_file) = &passphrase {
+        write_atomically(&passphrase_key_path(pdpw_file), key_file).await?;
+    }
+    write_atomically(pdpw_file, &encrypted).await?;
+    // only now, a failed save must leave the key that opens the old vault
+    match passphrase {
+        PassphraseKey::Rewrap(_, key_file) => {
+            write_atomically(&passphrase_key_path(pdpw_file), &key_file).await?;
+        }
+        PassphraseKey::Remove => tokio::fs::remove_file(passphrase_key_path(pdpw_file)).await?,
+        PassphraseKey::None | PassphraseKey::Unchanged(_) | PassphraseKey::Create(..) => {}
+    }
     Ok(())
 }
+
+/// Replaces `target` with `data` without ever leaving a partially written file
+/// behind: the bytes go to a temp file in the same directory, get fsynced and
+/// are then renamed over the original. A symlinked `target` stays a link, the
+/// file it points to gets replaced.
+pub(crate) async fn write_atomically(target: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let target = match tokio::fs::canonicalize(target).await {
+        Ok(resolved) => resolved,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => target.to_path_buf(),
+        Err(e) => return Err(e).with_context(|| format!("Couldn't resolve {}", target.display())),
+    };
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let data = data.to_vec();
+    let replace_dir = dir.clone();
+    tokio::task::spawn_blocking(move || replace_file(&target, &replace_dir, &data)).await??;
+    sync_dir(&dir).await
+}
+
+/// Writes `data` to a new temp file in `dir` and renames it over `target`.
+/// The temp file is removed again if anything fails.
+fn replace_file(target: &Path, dir: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let file_name = target
+        .file_name()
+        .with_context(|| format!("{} is not a file path", target.display()))?;
+    let mut prefix = OsString::from(".");
+    prefix.push(file_name);
+    prefix.push(".");
+    let mut tmp = tempfile::Builder::new()
+        .prefix(&prefix)
+        .suffix(".tmp")
+        .tempfile_in(dir)
+        .with_context(|| format!("Couldn't create a temp file in {}", dir.display()))?;
+    if let Ok(metadata) = std::fs::metadata(target) {
+        tmp.as_file().set_permissions(metadata.permissions())?;
+    }
+    tmp.write_all(data)
+        .with_context(|| format!("Couldn't write {}", tmp.path().display()))?;
+    tmp.as_file()
+        .sync_all()
+        .with_context(|| format!("Couldn't sync {}", tmp.path().display()))?;
+    tmp.persist(target)
+        .with_context(|| format!("Couldn't replace {}", target.display()))?;
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn sync_dir(dir: &Path) -> anyhow::Result<()> {
+    tokio::fs::File::open(dir)
+        .await?
+        .sync_all()
+        .await
+        .with_context(|| format!("Couldn't sync directory {}", dir.display()))
+}
+
+#[cfg(not(unix))]
+#[allow(clippy::unused_async)]
+async fn sync_dir(_dir: &Path) -> anyhow::Result<()> {
+    Ok(())
+}
+
+/// Encrypts `passwords` so that only whoever can open the vault can read them
+/// again, without touching anything on disk. Keeps unsaved changes of a
+/// locked editor, so it fails unless `keys` can unseal them again.
+pub(crate) async fn seal(
+    pdpw_file: &Path,
+    keys: &Keys,
+    passwords: &str,
+) -> anyhow::Result<Vec<u8>> {
+    let encryptor = if keys.recipients.is_empty() {
+        passphrase_encryptor(keys)?
+    } else {
+        let mut recipients = keys
+            .recipients
+            .iter()
+            .map(|r| parse_recipient(r))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if let Ok(Some(identity)) = load_passphrase_key(pdpw_file, keys).await {
+            recipients.push(Box::new(identity.to_public()));
+        }
+        age::Encryptor::with_recipients(recipients.iter().map(|r| r.as_ref() as _))?
+    };
+    let sealed = encrypt(encryptor, passwords, false)?;
+    // recipients added since the last save might be the only ones left
+    let round_trip = unseal(pdpw_file, &sealed, keys)
+        .await
+        .context("The changes could not be unlocked again, save them before locking")?;
+    if round_trip != passwords {
+        bail!("Verification of the sealed changes failed: content mismatch");
+    }
+    Ok(sealed)
+}
+
+pub(crate) async fn unseal(pdpw_file: &Path, sealed: &[u8], keys: &Keys) -> anyhow::Result<String> {
+    decrypt(pdpw_file, sealed, keys).await
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::kdf::vault_work_factor;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn pin_keys(pin: &str) -> Keys {
+        Keys {
+            pin: pin.into(),
+            keyfile: None,
+            recipients: vec![],
+            identity_files: vec![],
+            kdf: KdfPolicy::LogN(10),
+            previous: None,
+        }
+    }
+
+    /// A vault encrypted to a single age key and `secret` as fallback, with
+    /// the keys of someone who only has the identity file.
+    fn shared_vault(dir: &Path) -> (PathBuf, Keys) {
+        let identity = age::x25519::Identity::generate();
+        let identity_file = dir.join("identity.txt");
+        std::fs::write(&identity_file, identity.to_string().expose_secret()).unwrap();
+        let vault = dir.join("vault.pdpw");
+        let mut keys = pin_keys("");
+        keys.recipients = vec![identity.to_public().to_string()];
+        keys.identity_files = vec![identity_file];
+        let with_pin = Keys {
+            pin: "secret".into(),
+            ..keys.clone()
+        };
+        block_on(store_pdpw_file(&vault, &with_pin, "one", 0, None)).unwrap();
+        (vault, keys)
+    }
+
+    #[test]
+    fn reads_the_work_factor_of_shared_vaults() {
+        let dir = tempfile::tempdir().unwrap();
+        let (vault, keys) = shared_vault(dir.path());
+        // the vault itself only has recipient stanzas
+        assert_eq!(scrypt_work_factor(&std::fs::read(&vault).unwrap()), None);
+        assert_eq!(block_on(vault_work_factor(&vault)), Some(10));
+        // saving with a stronger policy wraps the key again
+        let stronger = Keys {
+            pin: "secret".into(),
+            kdf: KdfPolicy::LogN(11),
+            ..keys
+        };
+        block_on(store_pdpw_file(&vault, &stronger, "two", 0, None)).unwrap();
+        assert_eq!(block_on(vault_work_factor(&vault)), Some(11));
+        assert_eq!(block_on(load_pdpw_file(&vault, &stronger)).unwrap(), "two");
+    }
+
+    #[test]
+    fn keeps_the_pin_of_identity_users() {
+        let dir = tempfile::tempdir().unwrap();
+        let (vault, keys) = shared_vault(dir.path());
+        let key_file = std::fs::read(passphrase_key_path(&vault)).unwrap();
+
+        assert_eq!(block_on(load_pdpw_file(&vault, &keys)).unwrap(), "one");
+        assert!(block_on(store_pdpw_file(&vault, &keys, "two", 0, None)).is_err());
+
+        // a wrong pin still opens the vault with the identity file
+        let wrong_pin = Keys {
+            pin: "typo".into(),
+            ..keys.clone()
+        };
+        assert_eq!(block_on(load_pdpw_file(&vault, &wrong_pin)).unwrap(), "one");
+        assert!(block_on(store_pdpw_file(&vault, &wrong_pin, "two", 0, None)).is_err());
+
+        assert_eq!(
+            std::fs::read(passphrase_key_path(&vault)).unwrap(),
+            key_file
+        );
+        let pin_only = pin_keys("secret");
+        assert_eq!(block_on(load_pdpw_file(&vault, &pin_only)).unwrap(), "one");
+    }
+
+    #[test]
+    fn changes_the_pin_of_shared_vaults() {
+        let dir = tempfile::tempdir().unwrap();
+        let (vault, keys) = shared_vault(dir.path());
+        let new_pin = Keys {
+            pin: "new secret".into(),
+            previous: Some(Box::new(Keys {
+                pin: "secret".into(),
+                ..keys.clone()
+            })),
+            ..keys.clone()
+        };
+        block_on(store_pdpw_file(&vault, &new_pin, "two", 0, None)).unwrap();
+
+        assert_eq!(
+            block_on(load_pdpw_file(&vault, &pin_keys("new secret"))).unwrap(),
+            "two"
+        );
+        assert!(block_on(load_pdpw_file(&vault, &pin_keys("secret"))).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_a_new_passphrase_key_first() {
+        let dir = tempfile::tempdir().unwrap();
+        // short enough for the vault and its temp file, too long for the temp
+        // file of the passphrase key, so writing the key fails
+        let vault = dir.path().join(format!("{}.pdpw", "v".repeat(230)));
+        let keys = pin_keys("secret");
+        block_on(store_pdpw_file(&vault, &keys, "one", 0, None)).unwrap();
+
+        let with_recipient = Keys {
+            recipients: vec![age::x25519::Identity::generate().to_public().to_string()],
+            ..keys.clone()
+        };
+        assert!(block_on(store_pdpw_file(&vault, &with_recipient, "two", 0, None)).is_err());
+        // the vault didn't get encrypted to a key that isn't on disk
+        assert_eq!(block_on(load_pdpw_file(&vault, &keys)).unwrap(), "one");
+    }
+
+    #[test]
+    fn rotates_backups() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = dir.path().join("vault.pdpw");
+        let keys = pin_keys("secret");
+        for contents in ["one", "two", "three", "four"] {
+            block_on(store_pdpw_file(&vault, &keys, contents, 2, None)).unwrap();
+        }
+        let backups = block_on(list_backups(&vault)).unwrap();
+        let contents = backups
+            .iter()
+            .map(|backup| block_on(load_backup(&vault, &backup.path, &keys)).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(contents, ["three", "two"]);
+
+        // keeping fewer removes the older ones
+        block_on(store_pdpw_file(&vault, &keys, "five", 1, None)).unwrap();
+        let backups = block_on(list_backups(&vault)).unwrap();
+        assert_eq!(backups.len(), 1);
+        assert!(!backup_path(&vault, 2).exists());
+    }
+
+    #[test]
+    fn refuses_vaults_it_cannot_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = dir.path().join("vault.pdpw");
+        block_on(store_pdpw_file(&vault, &pin_keys("secret"), "one", 0, None)).unwrap();
+        let encrypted = std::fs::read(&vault).unwrap();
+
+        // encrypted to someone else only, we could never open it again
+        let mut keys = pin_keys("");
+        keys.recipients = vec![age::x25519::Identity::generate().to_public().to_string()];
+        let error = block_on(store_pdpw_file(&vault, &keys, "two", 0, None)).unwrap_err();
+        assert!(format!("{error}").starts_with("Verification of the encrypted vault failed"));
+        assert_eq!(std::fs::read(&vault).unwrap(), encrypted);
+    }
+
+    #[test]
+    fn seals_only_what_can_be_unsealed() {
+        let dir = tempfile::tempdir().unwrap();
+        let vault = dir.path().join("vault.pdpw");
+        let mut keys = pin_keys("secret");
+        let sealed = block_on(seal(&vault, &keys, "changes")).unwrap();
+        assert_eq!(block_on(unseal(&vault, &sealed, &keys)).unwrap(), "changes");
+
+        // a recipient added without saving, there is no passphrase key yet
+        keys.recipients = vec![age::x25519::Identity::generate().to_public().to_string()];
+        assert!(block_on(seal(&vault, &keys, "changes")).is_err());
+    }
+
+    #[test]
+    fn writes_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("vault.pdpw");
+        block_on(write_atomically(&target, b"one")).unwrap();
+        block_on(write_atomically(&target, b"two")).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"two");
+        // no temp files left behind
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn round_trips_armored_vaults() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vault.pdpw");
+        let keys = pin_keys("secret");
+        block_on(store_pdpw_file(&path, &keys, "ä: 1\n", 0, Some(true))).unwrap();
+        assert!(is_armored(&std::fs::read(&path).unwrap()));
+        assert_eq!(block_on(load_pdpw_file(&path, &keys)).unwrap(), "ä: 1\n");
+    }
+
+    #[test]
+    fn keeps_the_format_without_armor_option() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = pin_keys("secret");
+        for armored in [false, true] {
+            let path = dir.path().join(format!("{armored}.pdpw"));
+            block_on(store_pdpw_file(&path, &keys, "one", 0, Some(armored))).unwrap();
+            block_on(store_pdpw_file(&path, &keys, "two", 0, None)).unwrap();
+            assert_eq!(is_armored(&std::fs::read(&path).unwrap()), armored);
+            assert_eq!(block_on(load_pdpw_file(&path, &keys)).unwrap(), "two");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_through_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let synced = dir.path().join("synced");
+        std::fs::create_dir(&synced).unwrap();
+        let vault = synced.join("vault.pdpw");
+        std::fs::write(&vault, b"old").unwrap();
+        let link = dir.path().join("default.pdpw");
+        std::os::unix::fs::symlink(&vault, &link).unwrap();
+
+        block_on(write_atomically(&link, b"new")).unwrap();
+        assert!(link.symlink_metadata().unwrap().file_type().is_symlink());
+        assert_eq!(std::fs::read(&vault).unwrap(), b"new");
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::{Context, bail};
+use hmac::{Hmac, Mac, digest::KeyInit};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const OTPAUTH_PREFIX: &str = "otpauth://totp/";
+const DEFAULT_PERIOD: u64 = 30;
+const DEFAULT_DIGITS: u32 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    fn hmac(self, key: &[u8], message: &[u8]) -> Vec<u8> {
+        fn mac<M: Mac + KeyInit>(key: &[u8], message: &[u8]) -> Vec<u8> {
+            <M as Mac>::new_from_slice(key)
+                .expect("HMAC takes keys of any length")
+                .chain_update(message)
+                .finalize()
+                .into_bytes()
+                .to_vec()
+        }
+        match self {
+            Self::Sha1 => mac::<Hmac<Sha1>>(key, message),
+            Self::Sha256 => mac::<Hmac<Sha256>>(key, message),
+            Self::Sha512 => mac::<Hmac<Sha512>>(key, message),
+        }
+    }
+}
+
+/// A TOTP generator (RFC 6238), from an `otpauth://totp/...` URI or a bare
+/// base32 secret.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Totp {
+    secret: Vec<u8>,
+    algorithm: Algorithm,
+    digits: u32,
+    period: u64,
+}
+
+impl Totp {
+    pub(crate) fn parse(value: &str) -> anyhow::Result<Self> {
+        let value = value.trim();
+        let Some(uri) = value.strip_prefix(OTPAUTH_PREFIX) else {
+            if value.starts_with("otpauth://") {
+                bail!("Only time based one-time passwords (otpauth://totp/) are supported");
+            }
+            return Ok(Self {
+                secret: base32_decode(value)?,
+                algorithm: Algorithm::Sha1,
+                digits: DEFAULT_DIGITS,
+                period: DEFAULT_PERIOD,
+            });
+        };
+        let query = uri
+            .split_once('?')
+            .map(|(_, query)| query)
+            .unwrap_or_default();
+        let mut secret = None;
+        let mut totp = Self {
+            secret: vec![],
+            algorithm: Algorithm::Sha1,
+            digits: DEFAULT_DIGITS,
+            period: DEFAULT_PERIOD,
+        };
+        for (key, value) in query.split('&').filter_map(|pair| pair.split_once('=')) {
+            match key.to_lowercase().as_str() {
+                "secret" => secret = Some(base32_decode(value)?),
+                "algorithm" => {
+                    totp.algorithm = match value.to_uppercase().as_str() {
+                        "SHA1" => Algorithm::Sha1,
+                        "SHA256" => Algorithm::Sha256,
+                        "SHA512" => Algorithm::Sha512,
+                        other => bail!("Unknown TOTP algorithm {other}"),
+                    };
+                }
+                "digits" => {
+                    totp.digits = value
+                        .parse()
+                        .ok()
+                        .filter(|digits| (6..=10).contains(digits))
+                        .context("TOTP digits must be between 6 and 10")?;
+                }
+                "period" => {
+                    totp.period = value
+                        .parse()
+                        .ok()
+                        .filter(|period| *period > 0)
+                        .context("TOTP period must be a positive number of seconds")?;
+                }
+                _ => {}
+            }
+        }
+        totp.secret = secret.context("The otpauth URI has no secret")?;
+        Ok(totp)
+    }
+
+    /// The code for the given Unix time in seconds.
+    pub(crate) fn code_at(&self, time: u64) -> String {
+        let counter = time / self.period;
+        let mac = self.algorithm.hmac(&self.secret, &counter.to_be_bytes());
+        // dynamic truncation, RFC 4226 section 5.3
+        let offset = usize::from(mac[mac.len() - 1] & 0x0f);
+        let binary = u32::from_be_bytes([
+            mac[offset] & 0x7f,
+            mac[offset + 1],
+            mac[offset + 2],
+            mac[offset + 3],
+        ]);
+        let code = u64::from(binary) % 10u64.pow(self.digits);
+        format!("{code:0width$}", width = self.digits as usize)
+    }
+
+    /// The current code and how long it stays valid.
+    pub(crate) fn now(&self) -> (String, Duration) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        (
+            self.code_at(time),
+            Duration::from_secs(self.period - time % self.period),
+        )
+    }
+
+    pub(crate) fn period(&self) -> Duration {
+        Duration::from_secs(self.period)
+    }
+}
+
+/// Whether `value` looks like a TOTP secret stored on its own line.
+pub(crate) fn is_otpauth(value: &str) -> bool {
+    value.trim_start().starts_with(OTPAUTH_PREFIX)
+}
+
+/// Decodes RFC 4648 base32, ignoring case, padding and spaces as authenticator
+/// apps show secrets in groups.
+fn base32_decode(value: &str) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in value.chars().filter(|c| !matches!(c, ' ' | '-' | '=')) {
+        let digit = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => bail!("The TOTP secret is not valid base32"),
+        };
+        buffer = (buffer << 5) | digit;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits).to_le_bytes()[0]);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    if bytes.is_empty() {
+        bail!("The TOTP secret is empty");
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    /// RFC 6238 appendix B: time, SHA1, SHA256 and SHA512 code.
+    const VECTORS: [(u64, &str, &str, &str); 6] = [
+        (59, "94287082", "46119246", "90693936"),
+        (1_111_111_109, "07081804", "68084774", "25091201"),
+        (1_111_111_111, "14050471", "67062674", "99943326"),
+        (1_234_567_890, "89005924", "91819424", "93441116"),
+        (2_000_000_000, "69279037", "90698825", "38618901"),
+        (20_000_000_000, "65353130", "77737706", "47863826"),
+    ];
+
+    fn totp(algorithm: Algorithm, seed: &str) -> Totp {
+        Totp {
+            secret: seed.as_bytes().to_vec(),
+            algorithm,
+            digits: 8,
+            period: 30,
+        }
+    }
+
+    #[test]
+    fn rfc_6238() {
+        let sha1 = totp(Algorithm::Sha1, "12345678901234567890");
+        let sha256 = totp(Algorithm::Sha256, "12345678901234567890123456789012");
+        let sha512 = totp(
+            Algorithm::Sha512,
+            "1234567890123456789012345678901234567890123456789012345678901234",
+        );
+        for (time, code1, code256, code512) in VECTORS {
+            assert_eq!(sha1.code_at(time), code1, "SHA1 at {time}");
+            assert_eq!(sha256.code_at(time), code256, "SHA256 at {time}");
+            assert_eq!(sha512.code_at(time), code512, "SHA512 at {time}");
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let uri = "otpauth://totp/Example:alice?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&digits=8";
+        assert_eq!(
+            Totp::parse(uri).unwrap(),
+            totp(Algorithm::Sha1, "12345678901234567890")
+        );
+        let bare = Totp::parse("gezd gnbv gy3t qojq gezd gnbv gy3t qojq").unwrap();
+        assert_eq!(bare.code_at(59), "287082");
+        assert!(Totp::parse("otpauth://hotp/x?secret=GEZDGNBV").is_err());
+        assert!(Totp::parse("otpauth://totp/x?secret=GEZD&digits=4").is_err());
+        assert!(Totp::parse("not base32!").is_err());
+    }
+}
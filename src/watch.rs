@@ -0,0 +1,38 @@
+use iced::futures::{SinkExt, Stream};
+use notify::{RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+
+/// Yields whenever something touches `pdpw_file`, including our own saves.
+///
+/// The directory is watched instead of the file itself, as saving replaces
+/// the file and a watch on it would end with the first save.
+#[allow(clippy::ptr_arg)] // `Subscription::run_with` passes its data by reference
+pub(crate) fn watch_file(pdpw_file: &PathBuf) -> impl Stream<Item = ()> + use<> {
+    let pdpw_file = pdpw_file.clone();
+    iced::stream::channel(1, async move |mut output| {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let name = pdpw_file.file_name().map(ToOwned::to_owned);
+        let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            if let Ok(event) = event
+                && event.paths.iter().any(|p| p.file_name() == name.as_deref())
+            {
+                let _ = sender.send(());
+            }
+        });
+        let dir = match pdpw_file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let Ok(mut watcher) = watcher else {
+            return;
+        };
+        if watcher.watch(dir, RecursiveMode::NonRecursive).is_err() {
+            return;
+        }
+        while receiver.recv().await.is_some() {
+            if output.send(()).await.is_err() {
+                break;
+            }
+        }
+    })
+}